@@ -0,0 +1,150 @@
+use bytecode_vm::compiler::Compiler;
+use bytecode_vm::lexer;
+use bytecode_vm::value::Value;
+use bytecode_vm::vm::{InterpretResult, Vm};
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use std::collections::VecDeque;
+use std::io;
+
+const SCRIPTS: [(&str, &str); 3] = [
+  (
+    "fib",
+    "fn fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); } fib(20);",
+  ),
+  (
+    "loop",
+    "let sum = 0; for (let i = 0; i < 100000; i = i + 1) { sum = sum + i * 2; }",
+  ),
+  (
+    "lists",
+    "let total = 0; for i in 0..10000 { let list = [i, i + 1, i + 2]; total = total + list[1]; }",
+  ),
+];
+
+fn run(source_code: &str) {
+  let mut vm = Vm::with_output(Box::new(io::sink()));
+
+  let tokens = lexer::lex(String::from(source_code)).unwrap();
+  let script = Compiler::with_symbols(vm.symbols())
+    .compile(tokens)
+    .unwrap();
+
+  match vm.run(script) {
+    InterpretResult::Ok(_) => {}
+    result => panic!("unexpected result {:?}", result),
+  }
+}
+
+/// Scripts that spend most of their time on numbers and locals.
+const ARITHMETIC_SCRIPTS: [(&str, &str); 2] = [
+  (
+    "locals",
+    "{ let sum = 0; let i = 0; while (i < 100000) { sum = sum + i * 2 - i / 2; i = i + 1; } }",
+  ),
+  (
+    "mandelbrot",
+    "fn escapes(cx, cy) {
+      let x = 0; let y = 0; let i = 0;
+      while (i < 50 and x * x + y * y < 4) {
+        let next = x * x - y * y + cx;
+        y = 2 * x * y + cy;
+        x = next;
+        i = i + 1;
+      }
+      return i;
+    }
+    for (let py = 0; py < 20; py = py + 1) {
+      for (let px = 0; px < 40; px = px + 1) {
+        escapes(px / 20 - 1.5, py / 10 - 1);
+      }
+    }",
+  ),
+];
+
+fn scripts(c: &mut Criterion) {
+  let mut group = c.benchmark_group("scripts");
+
+  for (name, source_code) in SCRIPTS.iter() {
+    group.bench_with_input(
+      BenchmarkId::from_parameter(name),
+      source_code,
+      |b, source_code| b.iter(|| run(source_code)),
+    );
+  }
+
+  group.finish();
+}
+
+/// Named after the value representation the crate was built with,
+/// run once with and once without the nan-boxing feature to compare them.
+fn arithmetic(c: &mut Criterion) {
+  let representation = if cfg!(feature = "nan-boxing") {
+    "nan_boxed"
+  } else {
+    "enum"
+  };
+
+  let mut group = c.benchmark_group(format!("arithmetic/{}", representation));
+
+  for (name, source_code) in ARITHMETIC_SCRIPTS.iter() {
+    group.bench_with_input(
+      BenchmarkId::from_parameter(name),
+      source_code,
+      |b, source_code| b.iter(|| run(source_code)),
+    );
+  }
+
+  group.finish();
+}
+
+/// Pushes and pops values the way the vm evaluates `a + b` in a loop,
+/// comparing the `VecDeque` the vm used to have with a preallocated `Vec`.
+fn stack(c: &mut Criterion) {
+  let mut group = c.benchmark_group("stack");
+
+  group.bench_function("vec_deque", |b| {
+    let mut stack: VecDeque<Value> = VecDeque::new();
+
+    b.iter(|| {
+      for i in 0..1000 {
+        stack.push_back(Value::Number(i as f64));
+        stack.push_back(Value::Number(1.0));
+
+        let b = stack.pop_back().unwrap();
+        let a = stack.pop_back().unwrap();
+
+        if let (Value::Number(a), Value::Number(b)) = (a, b) {
+          stack.push_back(Value::Number(a + b));
+        }
+
+        black_box(stack.pop_back());
+      }
+    })
+  });
+
+  group.bench_function("vec", |b| {
+    let mut stack: Vec<Value> = Vec::with_capacity(256);
+
+    b.iter(|| {
+      for i in 0..1000 {
+        stack.push(Value::Number(i as f64));
+        stack.push(Value::Number(1.0));
+
+        let b = stack.pop().unwrap();
+        let a = stack.pop().unwrap();
+
+        if let (Value::Number(a), Value::Number(b)) = (a, b) {
+          stack.push(Value::Number(a + b));
+        }
+
+        black_box(stack.pop());
+      }
+    })
+  });
+
+  group.finish();
+}
+
+criterion_group!(benches, scripts, arithmetic, stack);
+criterion_main!(benches);
@@ -1,27 +1,126 @@
+use crate::symbol::Symbol;
+use crate::token::Span;
 use crate::value::Value;
 
-#[derive(Debug, PartialEq, Clone)]
+use std::collections::HashMap;
+use std::rc::Rc;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum OpCode {
   Constant(usize),
-  DefineGlobalVariable(usize),
+  /// Global variables are accessed by the slot the compiler gave their name.
+  DefineGlobal(usize),
   Boolean(bool),
-  AccessGlobalVariable(usize),
+  GetGlobal(usize),
+  SetGlobal(usize),
+  GetLocal(usize),
+  SetLocal(usize),
+  GetUpvalue(usize),
+  SetUpvalue(usize),
+  CloseUpvalue,
+  Closure(usize),
+  Class(usize),
+  Method(usize),
+  Inherit,
+  /// Pops the given number of values into a new list.
+  BuildList(usize),
+  /// Pops the given number of key value pairs into a new map.
+  BuildMap(usize),
+  GetIndex,
+  SetIndex,
+  Range,
+  /// Replaces the value on top of the stack with an iterator over it.
+  GetIterator,
+  /// Pushes the next value of the iterator on top of the stack
+  /// or jumps forward when the iterator is exhausted.
+  ForIter(usize),
+  GetSuper(usize),
+  GetProperty(usize),
+  SetProperty(usize),
+  JumpIfFalse(usize),
+  Jump(usize),
+  Loop(usize),
+  Call(usize),
   Negate,
   Return,
   Add,
   Subtract,
   Multiply,
   Divide,
+  Not,
+  Equal,
+  Greater,
+  GreaterEqual,
+  Less,
+  LessEqual,
   Nil,
   Print,
   Pop,
 }
 
+/// Position in the source code an instruction was compiled from.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Location {
+  pub line: usize,
+  pub column: usize,
+}
+
+impl From<&Span> for Location {
+  fn from(span: &Span) -> Self {
+    Location {
+      line: span.line,
+      column: span.column,
+    }
+  }
+}
+
+/// Consecutive instructions compiled from the same location.
+#[derive(Debug, PartialEq, Clone)]
+struct LocationRun {
+  /// Offset of the first instruction in the run.
+  starts_at: usize,
+  location: Location,
+}
+
+/// Identifies constants that can be shared by every instruction that uses them.
+///
+/// Numbers are compared by their bits so -0.0 and 0.0 are different
+/// constants and a NaN can be found again even though it's not equal to itself.
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+enum ConstantKey {
+  Number(u64),
+  String(Rc<str>),
+}
+
+impl ConstantKey {
+  /// Returns None for values that are never deduplicated, like functions.
+  fn from_value(value: &Value) -> Option<ConstantKey> {
+    match value {
+      Value::Number(number) => Some(ConstantKey::Number(number.to_bits())),
+      Value::String(string) => Some(ConstantKey::String(Rc::clone(string))),
+      _ => None,
+    }
+  }
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct Chunk {
   pub code: Vec<OpCode>,
   pub constants: Vec<Value>,
-  pub lines: Vec<usize>,
+  /// Index in `constants` of every constant that can be shared.
+  constant_indexes: HashMap<ConstantKey, usize>,
+  /// Names used by class, method, property and super instructions.
+  pub identifiers: Vec<Symbol>,
+  identifier_indexes: HashMap<Symbol, usize>,
+  /// Run-length encoded locations sorted by offset,
+  /// most instructions share their location with their neighbours.
+  locations: Vec<LocationRun>,
+}
+
+impl Default for Chunk {
+  fn default() -> Self {
+    Self::new()
+  }
 }
 
 impl Chunk {
@@ -29,49 +128,123 @@ impl Chunk {
     Chunk {
       code: Vec::new(),
       constants: Vec::<Value>::new(),
-      lines: Vec::new(),
+      constant_indexes: HashMap::new(),
+      identifiers: Vec::new(),
+      identifier_indexes: HashMap::new(),
+      locations: Vec::new(),
     }
   }
 
-  pub fn write(&mut self, opcode: OpCode, line: usize) {
+  pub fn write(&mut self, opcode: OpCode, location: Location) {
+    let offset = self.code.len();
+
     self.code.push(opcode);
 
-    self.lines.push(line);
+    match self.locations.last() {
+      Some(run) if run.location == location => {}
+      _ => self.locations.push(LocationRun {
+        starts_at: offset,
+        location,
+      }),
+    }
   }
 
-  pub fn write_constant(&mut self, opcode: fn(usize) -> OpCode, value: Value, line: usize) {
+  /// Returns the index of `value` in the constant pool,
+  /// adding it only if an equal constant isn't there yet.
+  pub fn add_constant(&mut self, value: Value) -> usize {
+    let key = ConstantKey::from_value(&value);
+
+    if let Some(index) = key.as_ref().and_then(|key| self.constant_indexes.get(key)) {
+      return *index;
+    }
+
+    let index = self.constants.len();
+
     self.constants.push(value);
 
-    self.lines.push(line);
+    if let Some(key) = key {
+      self.constant_indexes.insert(key, index);
+    }
+
+    index
+  }
 
-    let constant_index = self.constants.len() - 1;
+  pub fn write_constant(&mut self, opcode: fn(usize) -> OpCode, value: Value, location: Location) {
+    let constant_index = self.add_constant(value);
 
-    self.code.push(opcode(constant_index));
+    self.write(opcode(constant_index), location);
+  }
+
+  /// Returns the index of `symbol` in `identifiers`, adding it the first time.
+  pub fn add_identifier(&mut self, symbol: Symbol) -> usize {
+    if let Some(index) = self.identifier_indexes.get(&symbol) {
+      return *index;
+    }
+
+    let index = self.identifiers.len();
+
+    self.identifiers.push(symbol);
+    self.identifier_indexes.insert(symbol, index);
+
+    index
+  }
+
+  pub fn write_identifier(
+    &mut self,
+    opcode: fn(usize) -> OpCode,
+    symbol: Symbol,
+    location: Location,
+  ) {
+    let identifier_index = self.add_identifier(symbol);
+
+    self.write(opcode(identifier_index), location);
+  }
+
+  /// Returns the location the instruction at `offset` was compiled from.
+  pub fn location_of(&self, offset: usize) -> Location {
+    assert!(
+      offset < self.code.len(),
+      "offset {} is out of bounds for chunk with {} instructions",
+      offset,
+      self.code.len()
+    );
+
+    let run_index = self
+      .locations
+      .partition_point(|run| run.starts_at <= offset);
+
+    self.locations[run_index - 1].location
   }
 }
 
 #[cfg(test)]
 mod tests {
   use super::*;
+  use crate::symbol::SymbolTable;
+
+  use std::cell::RefCell;
+
+  fn location(line: usize, column: usize) -> Location {
+    Location { line, column }
+  }
 
   #[test]
   fn write_adds_opcode_to_chunk_code() {
     let mut chunk = Chunk::new();
 
     assert_eq!(chunk.code, vec![]);
-    assert_eq!(chunk.lines, vec![]);
     assert_eq!(chunk.constants, vec![]);
 
-    chunk.write(OpCode::Return, 1);
+    chunk.write(OpCode::Return, location(1, 1));
 
     assert_eq!(chunk.code, vec![OpCode::Return]);
-    assert_eq!(chunk.lines, vec![1]);
+    assert_eq!(chunk.location_of(0), location(1, 1));
     assert_eq!(chunk.constants, vec![]);
 
-    chunk.write(OpCode::Constant(1), 3);
+    chunk.write(OpCode::Constant(1), location(3, 5));
 
     assert_eq!(chunk.code, vec![OpCode::Return, OpCode::Constant(1)]);
-    assert_eq!(chunk.lines, vec![1, 3]);
+    assert_eq!(chunk.location_of(1), location(3, 5));
     assert_eq!(chunk.constants, vec![]);
   }
 
@@ -80,22 +253,119 @@ mod tests {
     let mut chunk = Chunk::new();
 
     assert_eq!(chunk.code, vec![]);
-    assert_eq!(chunk.lines, vec![]);
     assert_eq!(chunk.constants, vec![]);
 
-    chunk.write_constant(OpCode::Constant, Value::Number(3.0), 3);
+    chunk.write_constant(OpCode::Constant, Value::Number(3.0), location(3, 1));
 
     assert_eq!(chunk.constants, vec![Value::Number(3.0)]);
 
-    assert_eq!(chunk.lines, vec![3]);
+    assert_eq!(chunk.location_of(0), location(3, 1));
 
-    chunk.write_constant(OpCode::Constant, Value::Number(5.0), 4);
+    chunk.write_constant(OpCode::Constant, Value::Number(5.0), location(4, 1));
 
     assert_eq!(
       chunk.constants,
       vec![Value::Number(3.0), Value::Number(5.0)]
     );
 
-    assert_eq!(chunk.lines, vec![3, 4]);
+    assert_eq!(chunk.code, vec![OpCode::Constant(0), OpCode::Constant(1)]);
+    assert_eq!(chunk.location_of(1), location(4, 1));
+  }
+
+  #[test]
+  fn location_of_returns_the_location_of_every_instruction() {
+    let locations = [
+      location(1, 1),
+      location(1, 1),
+      location(1, 1),
+      location(1, 7),
+      location(2, 1),
+      location(2, 1),
+      location(4, 3),
+      location(1, 1),
+    ];
+
+    let mut chunk = Chunk::new();
+
+    for location in locations.iter() {
+      chunk.write(OpCode::Nil, *location);
+    }
+
+    for (offset, location) in locations.iter().enumerate() {
+      assert_eq!(*location, chunk.location_of(offset));
+    }
+
+    // Instructions sharing a location are stored once.
+    assert_eq!(5, chunk.locations.len());
+  }
+
+  #[test]
+  fn equal_constants_are_stored_once() {
+    let test_cases: Vec<(Vec<Value>, Vec<usize>)> = vec![
+      (
+        vec![Value::Number(1.0), Value::Number(2.0), Value::Number(1.0)],
+        vec![0, 1, 0],
+      ),
+      (vec![Value::Number(0.0), Value::Number(-0.0)], vec![0, 1]),
+      (
+        vec![Value::Number(f64::NAN), Value::Number(f64::NAN)],
+        vec![0, 0],
+      ),
+      (
+        vec![
+          Value::String(Rc::from("a")),
+          Value::String(Rc::from("b")),
+          Value::String(Rc::from("a")),
+        ],
+        vec![0, 1, 0],
+      ),
+      (
+        vec![
+          Value::List(Rc::new(RefCell::new(Vec::new()))),
+          Value::List(Rc::new(RefCell::new(Vec::new()))),
+        ],
+        vec![0, 1],
+      ),
+    ];
+
+    for (values, expected_indexes) in test_cases {
+      let mut chunk = Chunk::new();
+
+      let indexes: Vec<usize> = values
+        .into_iter()
+        .map(|value| chunk.add_constant(value))
+        .collect();
+
+      assert_eq!(expected_indexes, indexes);
+      assert_eq!(
+        expected_indexes.iter().max().unwrap() + 1,
+        chunk.constants.len()
+      );
+    }
+  }
+
+  #[test]
+  fn identifiers_are_stored_once() {
+    let mut symbols = SymbolTable::new();
+
+    let x = symbols.intern("x");
+    let y = symbols.intern("y");
+
+    let mut chunk = Chunk::new();
+
+    chunk.write_identifier(OpCode::GetProperty, x, location(1, 1));
+    chunk.write_identifier(OpCode::SetProperty, y, location(1, 1));
+    chunk.write_identifier(OpCode::Method, x, location(1, 1));
+
+    assert_eq!(
+      vec![
+        OpCode::GetProperty(0),
+        OpCode::SetProperty(1),
+        OpCode::Method(0)
+      ],
+      chunk.code
+    );
+    assert_eq!(vec![x, y], chunk.identifiers);
+    assert!(chunk.constants.is_empty());
   }
 }
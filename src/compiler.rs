@@ -1,8 +1,11 @@
-use crate::chunk::{Chunk, OpCode};
-use crate::token::{SourceLocation, Token};
-use crate::value::Value;
+use crate::chunk::{Chunk, Location, OpCode};
+use crate::symbol::SymbolTable;
+use crate::token::{Span, Token};
+use crate::value::{Function, UpvalueDescriptor, Value};
 
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::rc::Rc;
 
 #[non_exhaustive]
 struct Precedences;
@@ -16,11 +19,13 @@ impl Precedences {
   pub const AND: Precedence = 4; // and
   pub const EQUALITY: Precedence = 5; // == !=
   pub const COMPARISON: Precedence = 6; // < > <= >=
-  pub const TERM: Precedence = 7; // + -
-  pub const FACTOR: Precedence = 8; // * /
-  pub const UNARY: Precedence = 9; // ! -
-  pub const CALL: Precedence = 10; // . ()
-  pub const PRIMARY: Precedence = 11;
+  pub const RANGE: Precedence = 7; // ..
+  pub const TERM: Precedence = 8; // + -
+  pub const FACTOR: Precedence = 9; // * /
+  pub const UNARY: Precedence = 10; // ! -
+  pub const CALL: Precedence = 11; // . ()
+  #[allow(dead_code)]
+  pub const PRIMARY: Precedence = 12;
 }
 
 fn token_precedence(token: &Token) -> Precedence {
@@ -32,22 +37,163 @@ fn token_precedence(token: &Token) -> Precedence {
     And => Precedences::AND,
     Equal | NotEqual => Precedences::EQUALITY,
     GreaterThan | LessThan | GreaterThanOrEqual | LessThanOrEqual => Precedences::COMPARISON,
+    DotDot => Precedences::RANGE,
     Plus | Minus => Precedences::TERM,
     Star | Slash => Precedences::FACTOR,
-    Dot => Precedences::CALL,
+    Dot | LeftParen | LeftBracket => Precedences::CALL,
     _ => Precedences::NONE,
   }
 }
 
+/// Converts a number literal accepted by the lexer to its value.
+fn parse_number(number: &str) -> Option<f64> {
+  let digits: String = number
+    .chars()
+    .filter(|character| *character != '_')
+    .collect();
+
+  let radix = match digits.get(..2) {
+    Some("0x") => 16,
+    Some("0b") => 2,
+    Some("0o") => 8,
+    _ => return digits.parse::<f64>().ok(),
+  };
+
+  parse_radix(&digits[2..], radix)
+}
+
+/// Converts binary, octal or hexadecimal digits to the nearest double,
+/// like `str::parse` does for decimal ones, so literals of any size
+/// lose precision or become infinite instead of being rejected.
+fn parse_radix(digits: &str, radix: u32) -> Option<f64> {
+  if digits.is_empty() {
+    return None;
+  }
+
+  let bits_per_digit = radix.trailing_zeros();
+
+  let mut mantissa: u128 = 0;
+  let mut exponent: i32 = 0;
+
+  for character in digits.chars() {
+    let digit = character.to_digit(radix)? as u128;
+
+    if mantissa.leading_zeros() >= bits_per_digit {
+      mantissa = (mantissa << bits_per_digit) | digit;
+    } else {
+      // Only whether the digits that don't fit are all zeros
+      // matters, the mantissa has more bits than a double anyway.
+      mantissa |= (digit != 0) as u128;
+      exponent += bits_per_digit as i32;
+    }
+  }
+
+  Some(mantissa as f64 * 2_f64.powi(exponent))
+}
+
 type Parselet = fn(&mut Compiler);
 
+#[derive(Debug, PartialEq, Clone)]
+pub struct CompileError {
+  pub line: usize,
+  pub column: usize,
+  pub message: String,
+}
+
+#[derive(Debug)]
+struct Local {
+  name: String,
+  /// None while the variable initializer is being compiled.
+  depth: Option<usize>,
+  /// Whether a closure captures the variable, captured variables
+  /// are moved to the heap instead of being popped when their scope ends.
+  is_captured: bool,
+}
+
+/// Compilation state of a loop whose body is being compiled.
+#[derive(Debug)]
+struct Loop {
+  /// Where `continue` jumps to.
+  continue_target: usize,
+  /// Scope depth outside of the loop body, locals declared
+  /// deeper than it are discarded by `break` and `continue`.
+  scope_depth: usize,
+  /// `break` jumps that land after the loop once it is compiled.
+  break_jumps: Vec<usize>,
+}
+
+/// Compilation state of a class declaration.
+#[derive(Debug)]
+struct ClassCompiler {
+  has_superclass: bool,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum FunctionKind {
+  Script,
+  Function,
+  Method,
+  Initializer,
+}
+
+/// Compilation state of a single function.
+///
+/// Every function declaration gets its own state that is
+/// pushed while its body is being compiled.
+#[derive(Debug)]
+struct FunctionCompiler {
+  function: Function,
+  kind: FunctionKind,
+  /// Local variables in the order they were declared,
+  /// the index of a local is the stack slot it lives in.
+  locals: Vec<Local>,
+  scope_depth: usize,
+  /// The innermost loop being compiled is the last one.
+  loops: Vec<Loop>,
+}
+
+impl FunctionCompiler {
+  fn new(name: Option<String>, kind: FunctionKind) -> Self {
+    FunctionCompiler {
+      function: Function {
+        name,
+        arity: 0,
+        chunk: Chunk::new(),
+        upvalues: Vec::new(),
+      },
+      kind,
+      // Slot 0 is taken by the function being called,
+      // methods use it to store the instance they were called on.
+      locals: vec![Local {
+        name: match kind {
+          FunctionKind::Method | FunctionKind::Initializer => String::from("this"),
+          FunctionKind::Script | FunctionKind::Function => String::new(),
+        },
+        depth: Some(0),
+        is_captured: false,
+      }],
+      scope_depth: 0,
+      loops: Vec::new(),
+    }
+  }
+}
+
 pub struct Compiler {
-  tokens: Vec<(Token, SourceLocation)>,
+  tokens: Vec<(Token, Span)>,
   position: usize,
   is_in_error_state: bool,
-  chunk: Chunk,
+  errors: Vec<CompileError>,
+  /// The innermost function being compiled is the last one.
+  function_compilers: Vec<FunctionCompiler>,
+  /// The innermost class being compiled is the last one.
+  class_compilers: Vec<ClassCompiler>,
+  /// Whether the expression being parsed by a prefix parselet
+  /// can be followed by `=`.
+  can_assign: bool,
   prefix_parselets: HashMap<std::mem::Discriminant<Token>, Parselet>,
   infix_parselets: HashMap<std::mem::Discriminant<Token>, Parselet>,
+  /// Identifier names, shared with the vm that runs the compiled code.
+  symbols: Rc<RefCell<SymbolTable>>,
 }
 
 macro_rules! parselets {
@@ -62,22 +208,53 @@ macro_rules! parselets {
 }
 
 impl Compiler {
-  pub fn new() -> Self {
+  /// Creates a compiler that interns identifiers into `symbols`,
+  /// which must be the table of the vm that will run the compiled code
+  /// so globals get the slots the vm expects.
+  pub fn with_symbols(symbols: Rc<RefCell<SymbolTable>>) -> Self {
     Compiler {
+      symbols,
       tokens: Vec::new(),
       position: 0,
       is_in_error_state: false,
-      chunk: Chunk::new(),
+      errors: Vec::new(),
+      function_compilers: Vec::new(),
+      class_compilers: Vec::new(),
+      can_assign: false,
       prefix_parselets: parselets! {
         &Token::True => Compiler::literal,
         &Token::False => Compiler::literal,
         &Token::Nil => Compiler::literal,
         // TODO: can we get the discriminant without instatiating the variant?
         &Token::Number("any number".to_owned()) => Compiler::literal,
-        &Token::Identifier("any identifier".to_owned()) => Compiler::variable
+        &Token::String("any string".to_owned()) => Compiler::literal,
+        &Token::Identifier("any identifier".to_owned()) => Compiler::variable,
+        &Token::Minus => Compiler::unary,
+        &Token::Bang => Compiler::unary,
+        &Token::LeftParen => Compiler::grouping,
+        &Token::LeftBracket => Compiler::list,
+        // Blocks are statements, so a brace in an expression starts a map.
+        &Token::LeftBrace => Compiler::map,
+        &Token::This => Compiler::this,
+        &Token::Super => Compiler::super_
       },
       infix_parselets: parselets! {
-        &Token::Plus => Compiler::binary
+        &Token::Plus => Compiler::binary,
+        &Token::Minus => Compiler::binary,
+        &Token::Star => Compiler::binary,
+        &Token::Slash => Compiler::binary,
+        &Token::Equal => Compiler::binary,
+        &Token::NotEqual => Compiler::binary,
+        &Token::GreaterThan => Compiler::binary,
+        &Token::GreaterThanOrEqual => Compiler::binary,
+        &Token::LessThan => Compiler::binary,
+        &Token::LessThanOrEqual => Compiler::binary,
+        &Token::DotDot => Compiler::binary,
+        &Token::And => Compiler::and,
+        &Token::Or => Compiler::or,
+        &Token::LeftParen => Compiler::call,
+        &Token::LeftBracket => Compiler::index,
+        &Token::Dot => Compiler::dot
       },
     }
   }
@@ -85,16 +262,44 @@ impl Compiler {
   fn reset(&mut self) {
     self.position = 0;
     self.is_in_error_state = false;
+    self.errors = Vec::new();
+    self.function_compilers = vec![FunctionCompiler::new(None, FunctionKind::Script)];
+    self.class_compilers = Vec::new();
   }
 
-  fn consume(&mut self, expected_token: &Token) -> Option<(Token, SourceLocation)> {
+  fn function_compiler(&mut self) -> &mut FunctionCompiler {
+    self
+      .function_compilers
+      .last_mut()
+      .expect("there should always be a function being compiled")
+  }
+
+  fn chunk(&mut self) -> &mut Chunk {
+    &mut self.function_compiler().function.chunk
+  }
+
+  /// Writes an instruction that accesses the global variable called `name`.
+  fn write_global(&mut self, opcode: fn(usize) -> OpCode, name: &str, location: Location) {
+    let slot = self.symbols.borrow_mut().global_slot(name);
+
+    self.chunk().write(opcode(slot), location);
+  }
+
+  /// Interns `name` and writes an instruction that refers to it.
+  fn write_identifier(&mut self, opcode: fn(usize) -> OpCode, name: &str, location: Location) {
+    let symbol = self.symbols.borrow_mut().intern(name);
+
+    self.chunk().write_identifier(opcode, symbol, location);
+  }
+
+  fn consume(&mut self, expected_token: &Token) -> Option<(Token, Span)> {
     let (token, location) = self.tokens[self.position].clone();
 
     if std::mem::discriminant(&token) != std::mem::discriminant(expected_token) {
-      self.error(format!(
-        "expected {:?}, got {:?} at line {} and column {}",
-        expected_token, token, location.line, location.column
-      ));
+      self.error_at(
+        location,
+        format!("expected {:?}, got {:?}", expected_token, token),
+      );
 
       None
     } else {
@@ -103,7 +308,7 @@ impl Compiler {
     }
   }
 
-  fn consume_current_token(&mut self) -> (Token, SourceLocation) {
+  fn consume_current_token(&mut self) -> (Token, Span) {
     let (token, location) = self.tokens[self.position].clone();
 
     self.position += 1;
@@ -112,19 +317,31 @@ impl Compiler {
   }
 
   fn error(&mut self, message: String) {
+    self.error_at(self.current_token_location(), message);
+  }
+
+  fn error_at(&mut self, location: Span, message: String) {
     if self.is_in_error_state {
       return;
     }
 
     self.is_in_error_state = true;
 
-    println!("{}", message);
+    self.errors.push(CompileError {
+      line: location.line,
+      column: location.column,
+      message,
+    });
   }
 
   fn synchronize(&mut self) {
     self.is_in_error_state = false;
 
     loop {
+      if self.position > 0 && self.tokens[self.position - 1].0 == Token::Semicolon {
+        break;
+      }
+
       match self.current_token() {
         Token::Eof
         | Token::Class
@@ -133,6 +350,8 @@ impl Compiler {
         | Token::For
         | Token::If
         | Token::While
+        | Token::Break
+        | Token::Continue
         | Token::Print
         | Token::Return => break,
         _ => self.advance(),
@@ -149,7 +368,7 @@ impl Compiler {
     token.clone()
   }
 
-  fn current_token_location(&self) -> SourceLocation {
+  fn current_token_location(&self) -> Span {
     let (_token, location) = &self.tokens[self.position];
     location.clone()
   }
@@ -161,15 +380,29 @@ impl Compiler {
     {
       None => self.error("expected expression".to_owned()),
       Some(prefix_parselet) => {
+        // Only expressions with precedence lower than or equal to
+        // assignment can be the target of an assignment.
+        let can_assign = precedence <= Precedences::ASSIGNMENT;
+
+        self.can_assign = can_assign;
+
         prefix_parselet(self);
 
         while precedence <= token_precedence(&self.current_token()) {
-          let infix_parselet = self
+          match self
             .infix_parselets
             .get(&std::mem::discriminant(&self.current_token()))
-            .unwrap();
+          {
+            None => break,
+            Some(infix_parselet) => {
+              self.can_assign = can_assign;
+              infix_parselet(self)
+            }
+          }
+        }
 
-          infix_parselet(self);
+        if can_assign && self.current_token() == Token::Assign {
+          self.error("invalid assignment target".to_owned());
         }
       }
     }
@@ -179,27 +412,16 @@ impl Compiler {
     self.parse_precedence(Precedences::ASSIGNMENT);
   }
 
-  fn number(&mut self) {
-    let (token, location) = self.consume_current_token();
-
-    match token {
-      Token::Number(lexeme) => {
-        let value = lexeme.parse::<f64>().unwrap();
-        self
-          .chunk
-          .write_constant(OpCode::Constant, Value::Number(value), location.line);
-      }
-      token => panic!("expected number got {:?}", token),
-    }
-  }
-
   fn unary(&mut self) {
-    let (token, location) = self.tokens[self.position].clone();
+    let (token, location) = self.consume_current_token();
 
     self.parse_precedence(Precedences::UNARY);
 
     match token {
-      Token::Minus => self.chunk.write(OpCode::Negate, location.line),
+      Token::Minus => self
+        .chunk()
+        .write(OpCode::Negate, Location::from(&location)),
+      Token::Bang => self.chunk().write(OpCode::Not, Location::from(&location)),
       token => panic!("unhandled token {:?}", token),
     }
   }
@@ -207,23 +429,38 @@ impl Compiler {
   fn binary(&mut self) {
     let (token, location) = self.consume_current_token();
 
+    // Operands on the right must bind tighter than the operator
+    // so `1 - 2 - 3` is parsed as `(1 - 2) - 3`.
+    self.parse_precedence(token_precedence(&token) + 1);
+
     match token {
-      Token::Plus => {
-        self.parse_precedence(Precedences::TERM);
-        self.chunk.write(OpCode::Add, location.line);
-      }
-      Token::Minus => {
-        self.parse_precedence(Precedences::TERM);
-        self.chunk.write(OpCode::Subtract, location.line);
-      }
-      Token::Slash => {
-        self.parse_precedence(Precedences::FACTOR);
-        self.chunk.write(OpCode::Divide, location.line);
-      }
-      Token::Star => {
-        self.parse_precedence(Precedences::FACTOR);
-        self.chunk.write(OpCode::Multiply, location.line);
+      Token::Plus => self.chunk().write(OpCode::Add, Location::from(&location)),
+      Token::Minus => self
+        .chunk()
+        .write(OpCode::Subtract, Location::from(&location)),
+      Token::Slash => self
+        .chunk()
+        .write(OpCode::Divide, Location::from(&location)),
+      Token::Star => self
+        .chunk()
+        .write(OpCode::Multiply, Location::from(&location)),
+      Token::Equal => self.chunk().write(OpCode::Equal, Location::from(&location)),
+      Token::NotEqual => {
+        self.chunk().write(OpCode::Equal, Location::from(&location));
+        self.chunk().write(OpCode::Not, Location::from(&location));
       }
+      Token::GreaterThan => self
+        .chunk()
+        .write(OpCode::Greater, Location::from(&location)),
+      // Not written as `!(a < b)`, which would be true when either side is NaN.
+      Token::GreaterThanOrEqual => self
+        .chunk()
+        .write(OpCode::GreaterEqual, Location::from(&location)),
+      Token::LessThan => self.chunk().write(OpCode::Less, Location::from(&location)),
+      Token::LessThanOrEqual => self
+        .chunk()
+        .write(OpCode::LessEqual, Location::from(&location)),
+      Token::DotDot => self.chunk().write(OpCode::Range, Location::from(&location)),
       token => panic!("unexpected token {:?}", token),
     }
   }
@@ -232,92 +469,1390 @@ impl Compiler {
     let (token, location) = self.consume_current_token();
 
     match token {
-      Token::False => self.chunk.write(OpCode::Boolean(false), location.line),
-      Token::True => self.chunk.write(OpCode::Boolean(true), location.line),
-      Token::Nil => self.chunk.write(OpCode::Nil, location.line),
-      Token::Number(number) => match number.parse::<f64>() {
-        Ok(number) => {
-          self
-            .chunk
-            .write_constant(OpCode::Constant, Value::Number(number), location.line)
-        }
-        error => panic!("{:?}", error),
+      Token::False => self
+        .chunk()
+        .write(OpCode::Boolean(false), Location::from(&location)),
+      Token::True => self
+        .chunk()
+        .write(OpCode::Boolean(true), Location::from(&location)),
+      Token::Nil => self.chunk().write(OpCode::Nil, Location::from(&location)),
+      Token::Number(number) => match parse_number(&number) {
+        Some(value) => self.chunk().write_constant(
+          OpCode::Constant,
+          Value::Number(value),
+          Location::from(&location),
+        ),
+        None => self.error_at(location, format!("invalid number literal {}", number)),
       },
+      Token::String(string) => self.chunk().write_constant(
+        OpCode::Constant,
+        Value::String(Rc::from(string)),
+        Location::from(&location),
+      ),
       token => panic!("unexpected token {:?}", token),
     }
   }
 
   fn grouping(&mut self) {
+    self.consume(&Token::LeftParen);
     self.expression();
     self.consume(&Token::RightParen);
   }
 
   fn print_statement(&mut self) {
+    let location = Location::from(&self.current_token_location());
+
     self.consume(&Token::Print);
 
     self.expression();
 
-    self
-      .chunk
-      .write(OpCode::Print, self.current_token_location().line)
+    self.consume(&Token::Semicolon);
+
+    self.chunk().write(OpCode::Print, location)
   }
 
   fn expression_statement(&mut self) {
+    let location = Location::from(&self.current_token_location());
+
     self.expression();
 
-    self
-      .chunk
-      .write(OpCode::Pop, self.current_token_location().line);
+    self.consume(&Token::Semicolon);
+
+    self.chunk().write(OpCode::Pop, location);
   }
 
-  fn variable(&mut self) {
+  fn block(&mut self) {
+    self.consume(&Token::LeftBrace);
+
+    while !matches!(self.current_token(), Token::RightBrace | Token::Eof) {
+      self.declaration();
+    }
+
+    self.consume(&Token::RightBrace);
+  }
+
+  /// Writes a jump with a placeholder offset that
+  /// should be fixed later by calling `patch_jump`.
+  ///
+  /// Returns the index of the jump instruction.
+  fn emit_jump(&mut self, opcode: fn(usize) -> OpCode, location: Location) -> usize {
+    self.chunk().write(opcode(usize::MAX), location);
+
+    self.chunk().code.len() - 1
+  }
+
+  /// Makes the jump at `jump_index` land on the next instruction that will be written.
+  fn patch_jump(&mut self, jump_index: usize) {
+    let jump_offset = self.chunk().code.len() - jump_index - 1;
+
+    match &mut self.chunk().code[jump_index] {
+      OpCode::Jump(offset) | OpCode::JumpIfFalse(offset) | OpCode::ForIter(offset) => {
+        *offset = jump_offset
+      }
+      opcode => panic!("expected jump instruction, got {:?}", opcode),
+    }
+  }
+
+  fn emit_loop(&mut self, loop_starts_at: usize, location: Location) {
+    // The offset accounts for the Loop instruction itself because
+    // the vm will have moved past it when the jump happens.
+    let offset = self.chunk().code.len() - loop_starts_at + 1;
+
+    self.chunk().write(OpCode::Loop(offset), location);
+  }
+
+  /// Skips the right operand when the left one is falsey,
+  /// leaving the left operand on the stack as the result.
+  fn and(&mut self) {
     let (token, location) = self.consume_current_token();
 
-    match token {
-      Token::Identifier(variable_name) => self.chunk.write_constant(
-        OpCode::AccessGlobalVariable,
-        Value::Identifier(variable_name),
-        location.line,
-      ),
-      token => panic!("unexpected token {:?}", token),
+    let end_jump = self.emit_jump(OpCode::JumpIfFalse, Location::from(&location));
+
+    self.chunk().write(OpCode::Pop, Location::from(&location));
+
+    self.parse_precedence(token_precedence(&token) + 1);
+
+    self.patch_jump(end_jump);
+  }
+
+  /// Skips the right operand when the left one is truthy,
+  /// leaving the left operand on the stack as the result.
+  fn or(&mut self) {
+    let (token, location) = self.consume_current_token();
+
+    let else_jump = self.emit_jump(OpCode::JumpIfFalse, Location::from(&location));
+    let end_jump = self.emit_jump(OpCode::Jump, Location::from(&location));
+
+    self.patch_jump(else_jump);
+
+    self.chunk().write(OpCode::Pop, Location::from(&location));
+
+    self.parse_precedence(token_precedence(&token) + 1);
+
+    self.patch_jump(end_jump);
+  }
+
+  fn if_statement(&mut self) {
+    let (_token, location) = self.consume_current_token();
+
+    self.consume(&Token::LeftParen);
+    self.expression();
+    self.consume(&Token::RightParen);
+
+    let then_jump = self.emit_jump(OpCode::JumpIfFalse, Location::from(&location));
+
+    self.chunk().write(OpCode::Pop, Location::from(&location));
+
+    self.statement();
+
+    let else_jump = self.emit_jump(OpCode::Jump, Location::from(&location));
+
+    self.patch_jump(then_jump);
+
+    self.chunk().write(OpCode::Pop, Location::from(&location));
+
+    if self.current_token() == Token::Else {
+      self.advance();
+      self.statement();
     }
+
+    self.patch_jump(else_jump);
   }
 
-  fn let_declaration(&mut self) {
-    self.consume(&Token::Let);
+  fn while_statement(&mut self) {
+    let (_token, location) = self.consume_current_token();
 
-    if let Some((Token::Identifier(identifier), location)) =
-      self.consume(&Token::Identifier("any_identifier".to_owned()))
-    {
-      self.consume(&Token::Assign);
+    let loop_starts_at = self.chunk().code.len();
+
+    self.consume(&Token::LeftParen);
+    self.expression();
+    self.consume(&Token::RightParen);
+
+    let exit_jump = self.emit_jump(OpCode::JumpIfFalse, Location::from(&location));
+
+    self.chunk().write(OpCode::Pop, Location::from(&location));
+
+    self.begin_loop(loop_starts_at);
+
+    self.statement();
+
+    self.emit_loop(loop_starts_at, Location::from(&location));
+
+    self.patch_jump(exit_jump);
+
+    self.chunk().write(OpCode::Pop, Location::from(&location));
+
+    self.end_loop();
+  }
+
+  fn for_statement(&mut self) {
+    let (_token, location) = self.consume_current_token();
+
+    if self.current_token() != Token::LeftParen {
+      self.for_in_statement(location);
+      return;
+    }
+
+    // Variables declared by the initializer only live while the loop runs.
+    self.begin_scope();
+
+    self.consume(&Token::LeftParen);
+
+    match self.current_token() {
+      Token::Semicolon => self.advance(),
+      Token::Let => self.let_declaration(),
+      _ => self.expression_statement(),
+    }
+
+    let mut loop_starts_at = self.chunk().code.len();
+
+    let exit_jump = if self.current_token() == Token::Semicolon {
+      None
+    } else {
+      self.expression();
+
+      let exit_jump = self.emit_jump(OpCode::JumpIfFalse, Location::from(&location));
+
+      self.chunk().write(OpCode::Pop, Location::from(&location));
+
+      Some(exit_jump)
+    };
+
+    self.consume(&Token::Semicolon);
+
+    if self.current_token() != Token::RightParen {
+      // The increment is compiled before the body but runs after it,
+      // so the body jumps over it and loops back to it.
+      let body_jump = self.emit_jump(OpCode::Jump, Location::from(&location));
+
+      let increment_starts_at = self.chunk().code.len();
 
       self.expression();
 
-      self.chunk.write_constant(
-        OpCode::DefineGlobalVariable,
-        Value::Identifier(identifier),
-        location.line,
-      )
+      self.chunk().write(OpCode::Pop, Location::from(&location));
+
+      self.emit_loop(loop_starts_at, Location::from(&location));
+
+      loop_starts_at = increment_starts_at;
+
+      self.patch_jump(body_jump);
+    }
+
+    self.consume(&Token::RightParen);
+
+    self.begin_loop(loop_starts_at);
+
+    self.statement();
+
+    self.emit_loop(loop_starts_at, Location::from(&location));
+
+    if let Some(exit_jump) = exit_jump {
+      self.patch_jump(exit_jump);
+
+      self.chunk().write(OpCode::Pop, Location::from(&location));
     }
+
+    self.end_loop();
+
+    self.end_scope();
   }
 
-  pub fn compile(&mut self, tokens: Vec<(Token, SourceLocation)>) -> Chunk {
-    self.reset();
+  fn for_in_statement(&mut self, location: Span) {
+    let variable = self.consume(&Token::Identifier("any_identifier".to_owned()));
 
-    self.tokens = tokens;
+    self.consume(&Token::In);
 
-    loop {
-      if self.is_in_error_state {
-        self.synchronize();
+    self.begin_scope();
+
+    self.expression();
+
+    self
+      .chunk()
+      .write(OpCode::GetIterator, Location::from(&location));
+
+    // The iterator lives in a slot scripts can't name.
+    self.declare_local(String::from(" iterator"), location.clone());
+    self.mark_initialized();
+
+    let loop_starts_at = self.chunk().code.len();
+
+    let exit_jump = self.emit_jump(OpCode::ForIter, Location::from(&location));
+
+    self.begin_loop(loop_starts_at);
+
+    // Every iteration gets a new variable holding the value pushed by ForIter,
+    // closures created in the body capture the value of their own iteration.
+    self.begin_scope();
+
+    if let Some((Token::Identifier(name), variable_location)) = variable {
+      self.declare_local(name, variable_location);
+      self.mark_initialized();
+    }
+
+    self.statement();
+
+    self.end_scope();
+
+    self.emit_loop(loop_starts_at, Location::from(&location));
+
+    self.patch_jump(exit_jump);
+
+    self.end_loop();
+
+    self.end_scope();
+  }
+
+  /// Starts a loop whose `continue` statements jump to `continue_target`.
+  fn begin_loop(&mut self, continue_target: usize) {
+    let scope_depth = self.function_compiler().scope_depth;
+
+    self.function_compiler().loops.push(Loop {
+      continue_target,
+      scope_depth,
+      break_jumps: Vec::new(),
+    });
+  }
+
+  /// Ends the innermost loop, its `break` statements land on the next instruction.
+  fn end_loop(&mut self) {
+    if let Some(innermost_loop) = self.function_compiler().loops.pop() {
+      for break_jump in innermost_loop.break_jumps {
+        self.patch_jump(break_jump);
       }
+    }
+  }
 
-      match self.current_token() {
-        Token::Eof => return self.chunk.clone(),
-        Token::Print => self.print_statement(),
-        Token::Let => self.let_declaration(),
-        Token::Illegal(character) => panic!("illegal character {:?}", character),
-        _ => self.expression_statement(),
+  /// Emits the instructions to discard locals declared deeper than `scope_depth`
+  /// without forgetting them, code after the jump that leaves them may still use them.
+  fn discard_locals(&mut self, scope_depth: usize, location: Location) {
+    let opcodes: Vec<OpCode> = self
+      .function_compiler()
+      .locals
+      .iter()
+      .rev()
+      .take_while(|local| local.depth > Some(scope_depth))
+      .map(|local| {
+        if local.is_captured {
+          OpCode::CloseUpvalue
+        } else {
+          OpCode::Pop
+        }
+      })
+      .collect();
+
+    for opcode in opcodes {
+      self.chunk().write(opcode, location);
+    }
+  }
+
+  fn break_statement(&mut self) {
+    let (_token, location) = self.consume_current_token();
+
+    self.consume(&Token::Semicolon);
+
+    match self.function_compiler().loops.last() {
+      None => self.error_at(location, "can't use break outside of a loop".to_owned()),
+      Some(innermost_loop) => {
+        let scope_depth = innermost_loop.scope_depth;
+
+        self.discard_locals(scope_depth, Location::from(&location));
+
+        let break_jump = self.emit_jump(OpCode::Jump, Location::from(&location));
+
+        if let Some(innermost_loop) = self.function_compiler().loops.last_mut() {
+          innermost_loop.break_jumps.push(break_jump);
+        }
+      }
+    }
+  }
+
+  fn continue_statement(&mut self) {
+    let (_token, location) = self.consume_current_token();
+
+    self.consume(&Token::Semicolon);
+
+    match self.function_compiler().loops.last() {
+      None => self.error_at(location, "can't use continue outside of a loop".to_owned()),
+      Some(innermost_loop) => {
+        let scope_depth = innermost_loop.scope_depth;
+        let continue_target = innermost_loop.continue_target;
+
+        self.discard_locals(scope_depth, Location::from(&location));
+
+        self.emit_loop(continue_target, Location::from(&location));
       }
     }
   }
+
+  /// Looks for a local variable declared in the function
+  /// at `function_index` in the function compiler stack.
+  fn resolve_local(&mut self, function_index: usize, name: &str, location: &Span) -> Option<usize> {
+    let (slot, is_initialized) = self.function_compilers[function_index]
+      .locals
+      .iter()
+      .enumerate()
+      .rev()
+      .find(|(_slot, local)| local.name == name)
+      .map(|(slot, local)| (slot, local.depth.is_some()))?;
+
+    if !is_initialized {
+      self.error_at(
+        location.clone(),
+        format!("can't read local variable {} in its own initializer", name),
+      );
+    }
+
+    Some(slot)
+  }
+
+  /// Looks for a variable declared in one of the functions enclosing
+  /// the function at `function_index` and captures it as an upvalue
+  /// in every function between the declaration and the usage.
+  fn resolve_upvalue(
+    &mut self,
+    function_index: usize,
+    name: &str,
+    location: &Span,
+  ) -> Option<usize> {
+    if function_index == 0 {
+      return None;
+    }
+
+    let enclosing_function_index = function_index - 1;
+
+    if let Some(slot) = self.resolve_local(enclosing_function_index, name, location) {
+      self.function_compilers[enclosing_function_index].locals[slot].is_captured = true;
+
+      return Some(self.add_upvalue(
+        function_index,
+        UpvalueDescriptor {
+          is_local: true,
+          index: slot,
+        },
+      ));
+    }
+
+    let index = self.resolve_upvalue(enclosing_function_index, name, location)?;
+
+    Some(self.add_upvalue(
+      function_index,
+      UpvalueDescriptor {
+        is_local: false,
+        index,
+      },
+    ))
+  }
+
+  fn add_upvalue(&mut self, function_index: usize, upvalue: UpvalueDescriptor) -> usize {
+    let upvalues = &mut self.function_compilers[function_index].function.upvalues;
+
+    // Closures capture each variable only once.
+    if let Some(index) = upvalues.iter().position(|existing| *existing == upvalue) {
+      return index;
+    }
+
+    upvalues.push(upvalue);
+
+    upvalues.len() - 1
+  }
+
+  fn variable(&mut self) {
+    let (token, location) = self.consume_current_token();
+
+    match token {
+      Token::Identifier(variable_name) => self.named_variable(variable_name, location),
+      token => panic!("unexpected token {:?}", token),
+    }
+  }
+
+  fn this(&mut self) {
+    let (_token, location) = self.consume_current_token();
+
+    if self.class_compilers.is_empty() {
+      self.error_at(location, "can't use this outside of a class".to_owned());
+      return;
+    }
+
+    // `this` can't be assigned to.
+    self.can_assign = false;
+
+    self.named_variable(String::from("this"), location);
+  }
+
+  fn super_(&mut self) {
+    let (_token, location) = self.consume_current_token();
+
+    match self.class_compilers.last() {
+      None => {
+        self.error_at(location, "can't use super outside of a class".to_owned());
+        return;
+      }
+      Some(class_compiler) if !class_compiler.has_superclass => {
+        self.error_at(
+          location,
+          "can't use super in a class with no superclass".to_owned(),
+        );
+        return;
+      }
+      Some(_) => {}
+    }
+
+    self.consume(&Token::Dot);
+
+    if let Some((Token::Identifier(name), method_location)) =
+      self.consume(&Token::Identifier("any_identifier".to_owned()))
+    {
+      // Methods accessed through `super` can't be assigned to.
+      self.can_assign = false;
+
+      self.named_variable(String::from("this"), location.clone());
+      self.named_variable(String::from("super"), location);
+
+      self.write_identifier(OpCode::GetSuper, &name, Location::from(&method_location));
+    }
+  }
+
+  fn named_variable(&mut self, variable_name: String, location: Span) {
+    let is_assignment = self.can_assign && self.current_token() == Token::Assign;
+
+    let function_index = self.function_compilers.len() - 1;
+
+    let opcode = match self.resolve_local(function_index, &variable_name, &location) {
+      Some(slot) if is_assignment => Some(OpCode::SetLocal(slot)),
+      Some(slot) => Some(OpCode::GetLocal(slot)),
+      None => self
+        .resolve_upvalue(function_index, &variable_name, &location)
+        .map(|index| {
+          if is_assignment {
+            OpCode::SetUpvalue(index)
+          } else {
+            OpCode::GetUpvalue(index)
+          }
+        }),
+    };
+
+    if is_assignment {
+      self.advance();
+
+      self.expression();
+    }
+
+    match opcode {
+      Some(opcode) => self.chunk().write(opcode, Location::from(&location)),
+      None => {
+        let global_opcode: fn(usize) -> OpCode = if is_assignment {
+          OpCode::SetGlobal
+        } else {
+          OpCode::GetGlobal
+        };
+
+        self.write_global(global_opcode, &variable_name, Location::from(&location))
+      }
+    }
+  }
+
+  fn begin_scope(&mut self) {
+    self.function_compiler().scope_depth += 1;
+  }
+
+  fn end_scope(&mut self) {
+    self.function_compiler().scope_depth -= 1;
+
+    let location = Location::from(&self.current_token_location());
+
+    loop {
+      let function_compiler = self.function_compiler();
+
+      match function_compiler.locals.last() {
+        Some(local) if local.depth > Some(function_compiler.scope_depth) => {
+          let opcode = if local.is_captured {
+            OpCode::CloseUpvalue
+          } else {
+            OpCode::Pop
+          };
+
+          function_compiler.locals.pop();
+
+          self.chunk().write(opcode, location);
+        }
+        _ => break,
+      }
+    }
+  }
+
+  fn declare_local(&mut self, name: String, location: Span) {
+    let function_compiler = self.function_compiler();
+
+    let scope_depth = function_compiler.scope_depth;
+
+    let is_already_declared_in_scope = function_compiler
+      .locals
+      .iter()
+      .rev()
+      .take_while(|local| local.depth.is_none() || local.depth == Some(scope_depth))
+      .any(|local| local.name == name);
+
+    function_compiler.locals.push(Local {
+      name: name.clone(),
+      depth: None,
+      is_captured: false,
+    });
+
+    if is_already_declared_in_scope {
+      self.error_at(
+        location,
+        format!("variable {} is already declared in this scope", name),
+      );
+    }
+  }
+
+  /// Makes the last declared local visible to the code that follows.
+  fn mark_initialized(&mut self) {
+    let function_compiler = self.function_compiler();
+
+    let scope_depth = function_compiler.scope_depth;
+
+    if let Some(local) = function_compiler.locals.last_mut() {
+      local.depth = Some(scope_depth);
+    }
+  }
+
+  fn is_in_local_scope(&mut self) -> bool {
+    self.function_compiler().scope_depth > 0
+  }
+
+  fn let_declaration(&mut self) {
+    self.consume(&Token::Let);
+
+    if let Some((Token::Identifier(identifier), location)) =
+      self.consume(&Token::Identifier("any_identifier".to_owned()))
+    {
+      if self.is_in_local_scope() {
+        self.declare_local(identifier.clone(), location.clone());
+      }
+
+      self.consume(&Token::Assign);
+
+      self.expression();
+
+      self.consume(&Token::Semicolon);
+
+      if self.is_in_local_scope() {
+        // Locals live in the stack slot their initializer leaves the value in.
+        self.mark_initialized();
+      } else {
+        self.write_global(OpCode::DefineGlobal, &identifier, Location::from(&location))
+      }
+    }
+  }
+
+  /// Writes the implicit return at the end of the current
+  /// function body and stops compiling it.
+  fn end_function(&mut self) -> Function {
+    // Points at the closing brace of the body rather than
+    // whatever comes after the function.
+    let (_token, location) = &self.tokens[self.position.saturating_sub(1)];
+    let location = Location::from(location);
+
+    self.emit_implicit_return(location);
+
+    self
+      .function_compilers
+      .pop()
+      .expect("there should always be a function being compiled")
+      .function
+  }
+
+  /// Initializers always return the instance being initialized,
+  /// other functions return nil when they don't return a value.
+  fn emit_implicit_return(&mut self, location: Location) {
+    if self.function_compiler().kind == FunctionKind::Initializer {
+      self.chunk().write(OpCode::GetLocal(0), location);
+    } else {
+      self.chunk().write(OpCode::Nil, location);
+    }
+
+    self.chunk().write(OpCode::Return, location);
+  }
+
+  fn function(&mut self, name: String, kind: FunctionKind) {
+    let location = Location::from(&self.current_token_location());
+
+    self
+      .function_compilers
+      .push(FunctionCompiler::new(Some(name), kind));
+
+    self.begin_scope();
+
+    self.consume(&Token::LeftParen);
+
+    if self.current_token() != Token::RightParen {
+      loop {
+        if let Some((Token::Identifier(parameter), location)) =
+          self.consume(&Token::Identifier("any_identifier".to_owned()))
+        {
+          self.function_compiler().function.arity += 1;
+          self.declare_local(parameter, location);
+          self.mark_initialized();
+        }
+
+        if self.current_token() != Token::Comma {
+          break;
+        }
+
+        self.advance();
+      }
+    }
+
+    self.consume(&Token::RightParen);
+
+    self.block();
+
+    // The scope doesn't need to be closed because
+    // returning discards the whole function slot window.
+    let function = self.end_function();
+
+    self.chunk().write_constant(
+      OpCode::Closure,
+      Value::Function(Rc::new(function)),
+      location,
+    );
+  }
+
+  fn function_declaration(&mut self) {
+    self.consume(&Token::Function);
+
+    if let Some((Token::Identifier(name), location)) =
+      self.consume(&Token::Identifier("any_identifier".to_owned()))
+    {
+      if self.is_in_local_scope() {
+        self.declare_local(name.clone(), location.clone());
+        // Functions are initialized right away so they can call themselves.
+        self.mark_initialized();
+      }
+
+      self.function(name.clone(), FunctionKind::Function);
+
+      if !self.is_in_local_scope() {
+        self.write_global(OpCode::DefineGlobal, &name, Location::from(&location))
+      }
+    }
+  }
+
+  fn argument_list(&mut self) -> usize {
+    let mut argument_count = 0;
+
+    if self.current_token() != Token::RightParen {
+      loop {
+        self.expression();
+
+        argument_count += 1;
+
+        if self.current_token() != Token::Comma {
+          break;
+        }
+
+        self.advance();
+      }
+    }
+
+    self.consume(&Token::RightParen);
+
+    argument_count
+  }
+
+  fn call(&mut self) {
+    let (_token, location) = self.consume_current_token();
+
+    let argument_count = self.argument_list();
+
+    self
+      .chunk()
+      .write(OpCode::Call(argument_count), Location::from(&location));
+  }
+
+  fn list(&mut self) {
+    let (_token, location) = self.consume_current_token();
+
+    let mut element_count = 0;
+
+    if self.current_token() != Token::RightBracket {
+      loop {
+        self.expression();
+
+        element_count += 1;
+
+        if self.current_token() != Token::Comma {
+          break;
+        }
+
+        self.advance();
+      }
+    }
+
+    self.consume(&Token::RightBracket);
+
+    self
+      .chunk()
+      .write(OpCode::BuildList(element_count), Location::from(&location));
+  }
+
+  fn map(&mut self) {
+    let (_token, location) = self.consume_current_token();
+
+    let mut entry_count = 0;
+
+    if self.current_token() != Token::RightBrace {
+      loop {
+        self.expression();
+
+        self.consume(&Token::Colon);
+
+        self.expression();
+
+        entry_count += 1;
+
+        if self.current_token() != Token::Comma {
+          break;
+        }
+
+        self.advance();
+      }
+    }
+
+    self.consume(&Token::RightBrace);
+
+    self
+      .chunk()
+      .write(OpCode::BuildMap(entry_count), Location::from(&location));
+  }
+
+  fn index(&mut self) {
+    let can_assign = self.can_assign;
+
+    let (_token, location) = self.consume_current_token();
+
+    self.expression();
+
+    self.consume(&Token::RightBracket);
+
+    if can_assign && self.current_token() == Token::Assign {
+      self.advance();
+
+      self.expression();
+
+      self
+        .chunk()
+        .write(OpCode::SetIndex, Location::from(&location));
+    } else {
+      self
+        .chunk()
+        .write(OpCode::GetIndex, Location::from(&location));
+    }
+  }
+
+  fn return_statement(&mut self) {
+    let (_token, location) = self.consume_current_token();
+
+    if self.function_compiler().kind == FunctionKind::Script {
+      self.error_at(
+        location.clone(),
+        "can't return from top-level code".to_owned(),
+      );
+    }
+
+    if self.current_token() == Token::Semicolon {
+      self.advance();
+      self.emit_implicit_return(Location::from(&location));
+      return;
+    }
+
+    if self.function_compiler().kind == FunctionKind::Initializer {
+      self.error_at(
+        location.clone(),
+        "can't return a value from an initializer".to_owned(),
+      );
+    }
+
+    self.expression();
+    self.consume(&Token::Semicolon);
+
+    self
+      .chunk()
+      .write(OpCode::Return, Location::from(&location));
+  }
+
+  fn dot(&mut self) {
+    let can_assign = self.can_assign;
+
+    self.consume(&Token::Dot);
+
+    if let Some((Token::Identifier(name), location)) =
+      self.consume(&Token::Identifier("any_identifier".to_owned()))
+    {
+      if can_assign && self.current_token() == Token::Assign {
+        self.advance();
+
+        self.expression();
+
+        self.write_identifier(OpCode::SetProperty, &name, Location::from(&location));
+      } else {
+        self.write_identifier(OpCode::GetProperty, &name, Location::from(&location));
+      }
+    }
+  }
+
+  fn method(&mut self) {
+    if let Some((Token::Identifier(name), location)) =
+      self.consume(&Token::Identifier("any_identifier".to_owned()))
+    {
+      let kind = if name == "init" {
+        FunctionKind::Initializer
+      } else {
+        FunctionKind::Method
+      };
+
+      self.function(name.clone(), kind);
+
+      self.write_identifier(OpCode::Method, &name, Location::from(&location));
+    }
+  }
+
+  fn class_declaration(&mut self) {
+    self.consume(&Token::Class);
+
+    if let Some((Token::Identifier(name), location)) =
+      self.consume(&Token::Identifier("any_identifier".to_owned()))
+    {
+      if self.is_in_local_scope() {
+        self.declare_local(name.clone(), location.clone());
+      }
+
+      self.write_identifier(OpCode::Class, &name, Location::from(&location));
+
+      if self.is_in_local_scope() {
+        self.mark_initialized();
+      } else {
+        self.write_global(OpCode::DefineGlobal, &name, Location::from(&location));
+      }
+
+      self.class_compilers.push(ClassCompiler {
+        has_superclass: false,
+      });
+
+      if self.current_token() == Token::LessThan {
+        self.advance();
+
+        if let Some((Token::Identifier(superclass_name), superclass_location)) =
+          self.consume(&Token::Identifier("any_identifier".to_owned()))
+        {
+          if superclass_name == name {
+            self.error_at(
+              superclass_location.clone(),
+              "a class can't inherit from itself".to_owned(),
+            );
+          }
+
+          self.named_variable(superclass_name, superclass_location.clone());
+
+          // Methods find the superclass through a local called `super`.
+          self.begin_scope();
+          self.declare_local(String::from("super"), superclass_location);
+          self.mark_initialized();
+
+          self.named_variable(name.clone(), location.clone());
+          self
+            .chunk()
+            .write(OpCode::Inherit, Location::from(&location));
+
+          if let Some(class_compiler) = self.class_compilers.last_mut() {
+            class_compiler.has_superclass = true;
+          }
+        }
+      }
+
+      // Methods are attached to the class on top of the stack.
+      self.named_variable(name, location.clone());
+
+      self.consume(&Token::LeftBrace);
+
+      while !matches!(self.current_token(), Token::RightBrace | Token::Eof)
+        && !self.is_in_error_state
+      {
+        self.method();
+      }
+
+      self.consume(&Token::RightBrace);
+
+      self.chunk().write(OpCode::Pop, Location::from(&location));
+
+      if let Some(ClassCompiler {
+        has_superclass: true,
+      }) = self.class_compilers.pop()
+      {
+        self.end_scope();
+      }
+    }
+  }
+
+  fn statement(&mut self) {
+    match self.current_token() {
+      Token::Print => self.print_statement(),
+      Token::If => self.if_statement(),
+      Token::While => self.while_statement(),
+      Token::For => self.for_statement(),
+      Token::Break => self.break_statement(),
+      Token::Continue => self.continue_statement(),
+      Token::Return => self.return_statement(),
+      Token::LeftBrace => {
+        self.begin_scope();
+        self.block();
+        self.end_scope();
+      }
+      _ => self.expression_statement(),
+    }
+  }
+
+  fn declaration(&mut self) {
+    let declaration_starts_at = self.position;
+
+    match self.current_token() {
+      Token::Let => self.let_declaration(),
+      Token::Function => self.function_declaration(),
+      Token::Class => self.class_declaration(),
+      Token::Illegal(character) => {
+        self.error(format!("illegal character {:?}", character));
+        self.advance();
+      }
+      _ => self.statement(),
+    }
+
+    if self.is_in_error_state {
+      // Declarations that fail before consuming any tokens
+      // would be compiled again forever if nothing was skipped.
+      if self.position == declaration_starts_at {
+        self.advance();
+      }
+
+      self.synchronize();
+    }
+  }
+
+  pub fn compile(&mut self, tokens: Vec<(Token, Span)>) -> Result<Function, Vec<CompileError>> {
+    self.reset();
+
+    self.tokens = tokens;
+
+    while self.current_token() != Token::Eof {
+      self.declaration();
+    }
+
+    let function = self.end_function();
+
+    if !self.errors.is_empty() {
+      return Err(std::mem::take(&mut self.errors));
+    }
+
+    Ok(function)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::lexer;
+  use crate::vm::Vm;
+
+  fn compiler() -> Compiler {
+    Compiler::with_symbols(Vm::new().symbols())
+  }
+
+  #[test]
+  fn local_scopes() {
+    let test_cases: Vec<(&str, Vec<CompileError>)> = vec![
+      (
+        "{ let a = a; }",
+        vec![CompileError {
+          line: 1,
+          column: 11,
+          message: String::from("can't read local variable a in its own initializer"),
+        }],
+      ),
+      (
+        "let a = 1; { let a = a + 1; }",
+        vec![CompileError {
+          line: 1,
+          column: 22,
+          message: String::from("can't read local variable a in its own initializer"),
+        }],
+      ),
+      (
+        "{ let a = 1; let a = 2; }",
+        vec![CompileError {
+          line: 1,
+          column: 18,
+          message: String::from("variable a is already declared in this scope"),
+        }],
+      ),
+      ("let a = 1; let a = 2;", vec![]),
+      ("{ let a = 1; { let b = a; } }", vec![]),
+    ];
+
+    for (input, expected_errors) in test_cases {
+      let tokens = lexer::lex(String::from(input)).unwrap();
+
+      let errors = compiler().compile(tokens).err().unwrap_or_default();
+
+      assert_eq!(expected_errors, errors);
+    }
+  }
+
+  #[test]
+  fn invalid_assignment_targets() {
+    let test_cases: Vec<&str> = vec![
+      "let a = 1; let b = 2; a + b = 3;",
+      "1 = 2;",
+      "let a = 1; -a = 2;",
+      "let a = 1; !a = true;",
+      "let a = 1; (a) = 2;",
+      "let a = 1; let b = 2; a == b = 3;",
+    ];
+
+    for input in test_cases {
+      let tokens = lexer::lex(String::from(input)).unwrap();
+
+      let errors = compiler().compile(tokens).unwrap_err();
+
+      assert_eq!(1, errors.len());
+      assert_eq!("invalid assignment target", errors[0].message);
+    }
+  }
+
+  #[test]
+  fn return_outside_of_functions() {
+    let test_cases: Vec<(&str, Vec<CompileError>)> = vec![
+      (
+        "return 1;",
+        vec![CompileError {
+          line: 1,
+          column: 1,
+          message: String::from("can't return from top-level code"),
+        }],
+      ),
+      (
+        "{ return; }",
+        vec![CompileError {
+          line: 1,
+          column: 3,
+          message: String::from("can't return from top-level code"),
+        }],
+      ),
+      ("fn f() { return 1; }", vec![]),
+      ("fn f() { { return; } }", vec![]),
+    ];
+
+    for (input, expected_errors) in test_cases {
+      let tokens = lexer::lex(String::from(input)).unwrap();
+
+      let errors = compiler().compile(tokens).err().unwrap_or_default();
+
+      assert_eq!(expected_errors, errors);
+    }
+  }
+
+  #[test]
+  fn this_and_initializers() {
+    let test_cases: Vec<(&str, Vec<CompileError>)> = vec![
+      (
+        "print this;",
+        vec![CompileError {
+          line: 1,
+          column: 7,
+          message: String::from("can't use this outside of a class"),
+        }],
+      ),
+      (
+        "fn f() { return this; }",
+        vec![CompileError {
+          line: 1,
+          column: 17,
+          message: String::from("can't use this outside of a class"),
+        }],
+      ),
+      (
+        "class A { init() { return 1; } }",
+        vec![CompileError {
+          line: 1,
+          column: 20,
+          message: String::from("can't return a value from an initializer"),
+        }],
+      ),
+      ("class A { init() { return; } }", vec![]),
+      ("class A { f() { return this; } }", vec![]),
+      ("class A { f() { fn g() { return this; } } }", vec![]),
+    ];
+
+    for (input, expected_errors) in test_cases {
+      let tokens = lexer::lex(String::from(input)).unwrap();
+
+      let errors = compiler().compile(tokens).err().unwrap_or_default();
+
+      assert_eq!(expected_errors, errors);
+    }
+  }
+
+  #[test]
+  fn super_outside_of_subclasses() {
+    let test_cases: Vec<(&str, Vec<CompileError>)> = vec![
+      (
+        "super.f();",
+        vec![CompileError {
+          line: 1,
+          column: 1,
+          message: String::from("can't use super outside of a class"),
+        }],
+      ),
+      (
+        "class A { f() { super.f(); } }",
+        vec![CompileError {
+          line: 1,
+          column: 17,
+          message: String::from("can't use super in a class with no superclass"),
+        }],
+      ),
+      (
+        "class A < A {}",
+        vec![CompileError {
+          line: 1,
+          column: 11,
+          message: String::from("a class can't inherit from itself"),
+        }],
+      ),
+      ("class A {} class B < A { f() { super.f(); } }", vec![]),
+    ];
+
+    for (input, expected_errors) in test_cases {
+      let tokens = lexer::lex(String::from(input)).unwrap();
+
+      let errors = compiler().compile(tokens).err().unwrap_or_default();
+
+      assert_eq!(expected_errors, errors);
+    }
+  }
+
+  #[test]
+  fn break_and_continue_outside_of_loops() {
+    let test_cases: Vec<(&str, Vec<CompileError>)> = vec![
+      (
+        "break;",
+        vec![CompileError {
+          line: 1,
+          column: 1,
+          message: String::from("can't use break outside of a loop"),
+        }],
+      ),
+      (
+        "{ continue; }",
+        vec![CompileError {
+          line: 1,
+          column: 3,
+          message: String::from("can't use continue outside of a loop"),
+        }],
+      ),
+      (
+        "while (true) { fn f() { break; } }",
+        vec![CompileError {
+          line: 1,
+          column: 25,
+          message: String::from("can't use break outside of a loop"),
+        }],
+      ),
+      ("while (true) break;", vec![]),
+      ("for (;;) { if (true) continue; }", vec![]),
+    ];
+
+    for (input, expected_errors) in test_cases {
+      let tokens = lexer::lex(String::from(input)).unwrap();
+
+      let errors = compiler().compile(tokens).err().unwrap_or_default();
+
+      assert_eq!(expected_errors, errors);
+    }
+  }
+
+  #[test]
+  fn radix_literals_of_any_size_are_rounded_like_decimal_ones() {
+    let test_cases: Vec<(String, f64)> = vec![
+      (String::from("0xff"), 255.0),
+      (String::from("0b1_0000_0000"), 256.0),
+      (String::from("0o777"), 511.0),
+      (
+        String::from("0xffff_ffff_ffff_ffff"),
+        18446744073709551615.0,
+      ),
+      (
+        String::from("0x1_0000_0000_0000_0000"),
+        18446744073709551616.0,
+      ),
+      // 2^53 + 1 is halfway between two doubles and rounds to even.
+      (String::from("0x20_0000_0000_0001"), 9007199254740992.0),
+      (String::from("0x20_0000_0000_0003"), 9007199254740996.0),
+      (format!("0x{}", "f".repeat(40)), 2_f64.powi(160)),
+      (format!("0x8{}1", "0".repeat(40)), 2_f64.powi(167)),
+      (format!("0x1{}", "0".repeat(300)), f64::INFINITY),
+      (format!("0b{}", "1".repeat(200)), 2_f64.powi(200)),
+    ];
+
+    for (literal, expected_number) in test_cases {
+      assert_eq!(Some(expected_number), parse_number(&literal), "{}", literal);
+    }
+
+    // Decimal literals are rounded the same way.
+    assert_eq!(
+      parse_number("18446744073709551616"),
+      parse_number("0x1_0000_0000_0000_0000")
+    );
+    assert_eq!(
+      parse_number("1e400"),
+      parse_number(&format!("0x1{}", "0".repeat(300)))
+    );
+  }
+
+  /// Lines of every instruction in `function`, followed by the lines
+  /// of the functions declared in it.
+  fn instruction_lines(function: &Function) -> Vec<usize> {
+    let mut lines: Vec<usize> = (0..function.chunk.code.len())
+      .map(|offset| function.chunk.location_of(offset).line)
+      .collect();
+
+    for constant in function.chunk.constants.iter() {
+      if let Value::Function(function) = constant {
+        lines.extend(instruction_lines(function));
+      }
+    }
+
+    lines
+  }
+
+  #[test]
+  fn every_instruction_maps_to_its_source_line() {
+    let test_cases: Vec<(&str, Vec<usize>)> = vec![
+      ("print 1;", vec![1, 1, 1, 1]),
+      ("let a = 1;\nprint a\n  + 2;", vec![1, 1, 2, 3, 3, 2, 3, 3]),
+      (
+        "let a = 1;\nwhile (a < 3)\n  a = a + 1;",
+        vec![1, 1, 2, 2, 2, 2, 2, 3, 3, 3, 3, 3, 2, 2, 3, 3],
+      ),
+      (
+        "fn f() {\n  return 1;\n}\nf();",
+        vec![1, 1, 4, 4, 4, 4, 4, 2, 2, 3, 3],
+      ),
+    ];
+
+    for (input, expected_lines) in test_cases {
+      let tokens = lexer::lex(String::from(input)).unwrap();
+
+      let script = compiler().compile(tokens).unwrap();
+
+      assert_eq!(expected_lines, instruction_lines(&script));
+    }
+  }
+
+  #[test]
+  fn repeated_constants_are_stored_once() {
+    let test_cases: Vec<(&str, usize, usize)> = vec![
+      // Globals are accessed by slot and don't need a constant.
+      ("let x = 1; print x + x + x; x = x;", 1, 0),
+      ("class A {} let a = A(); a.x = 1; print a.x + a.x;", 1, 2),
+      ("print 1 + 1 + 2;", 2, 0),
+      (r#"print "a" + "a";"#, 1, 0),
+      ("print 1 + 1.0 + 0x1;", 1, 0),
+      ("let x = 1; fn f() { return x + x; } print f() + x;", 2, 0),
+    ];
+
+    for (input, expected_constants, expected_identifiers) in test_cases {
+      let tokens = lexer::lex(String::from(input)).unwrap();
+
+      let script = compiler().compile(tokens).unwrap();
+
+      assert_eq!(expected_constants, script.chunk.constants.len());
+      assert_eq!(expected_identifiers, script.chunk.identifiers.len());
+    }
+  }
+
+  #[test]
+  fn identifiers_are_interned_into_the_shared_symbol_table() {
+    let symbols = Rc::new(RefCell::new(SymbolTable::new()));
+
+    for input in ["let x = 1;", "print x;", "fn f(y) { return x + y; }"] {
+      let tokens = lexer::lex(String::from(input)).unwrap();
+
+      Compiler::with_symbols(Rc::clone(&symbols))
+        .compile(tokens)
+        .unwrap();
+    }
+
+    // Locals like y are resolved to slots and never interned.
+    assert_eq!(2, symbols.borrow().len());
+  }
 }
@@ -5,58 +5,216 @@
 /// A dissasembler goes in the other direction: given a blob
 /// of machine code, it spits out a textual listing of their instructions.
 use crate::chunk::{Chunk, OpCode};
+use crate::symbol::{Symbol, SymbolTable};
 use crate::value::Value;
 
-pub fn disassemble_chunk(chunk: &Chunk) {
-  let mut offset = 0;
-
-  while offset < chunk.code.len() {
-    offset = disassemble_instruction(chunk, offset);
-  }
+/// Prints the listing of `chunk`, `symbols` must be the table
+/// the chunk was compiled with to show the names it refers to.
+pub fn disassemble_chunk(chunk: &Chunk, symbols: &SymbolTable) {
+  print!("{}", disassemble(chunk, symbols));
 }
 
-fn disassemble_instruction(chunk: &Chunk, offset: usize) -> usize {
-  print!("{offset:>0width$} ", offset = offset, width = 4);
+/// Returns the listing of `chunk`, one instruction per line.
+pub fn disassemble(chunk: &Chunk, symbols: &SymbolTable) -> String {
+  let mut listing = String::new();
+  let mut previous_line = None;
+
+  for (offset, opcode) in chunk.code.iter().enumerate() {
+    let location = chunk.location_of(offset);
+
+    listing.push_str(&format!("{offset:>0width$} ", offset = offset, width = 4));
+
+    if previous_line == Some(location.line) {
+      listing.push_str("   | ");
+    } else {
+      listing.push_str(&format!("{:>4}:{:<3} ", location.line, location.column));
+    }
+
+    listing.push_str(&disassemble_instruction(chunk, symbols, offset, opcode));
+    listing.push('\n');
 
-  if offset > 0 && chunk.lines[offset] == chunk.lines[offset - 1] {
-    print!("| ");
-  } else {
-    print!("{} ", chunk.lines[offset]);
+    previous_line = Some(location.line);
   }
 
-  match &chunk.code[offset] {
+  listing
+}
+
+fn disassemble_instruction(
+  chunk: &Chunk,
+  symbols: &SymbolTable,
+  offset: usize,
+  opcode: &OpCode,
+) -> String {
+  match opcode {
     OpCode::Constant(index) => {
-      indexed_instruction(OpCode::Constant(*index), &chunk.constants[*index], offset)
+      indexed_instruction(OpCode::Constant(*index), &chunk.constants[*index])
+    }
+    OpCode::Return => simple_instruction(OpCode::Return),
+    OpCode::Negate => simple_instruction(OpCode::Negate),
+    OpCode::Add => simple_instruction(OpCode::Add),
+    OpCode::Subtract => simple_instruction(OpCode::Subtract),
+    OpCode::Multiply => simple_instruction(OpCode::Multiply),
+    OpCode::Divide => simple_instruction(OpCode::Divide),
+    OpCode::Not => simple_instruction(OpCode::Not),
+    OpCode::Equal => simple_instruction(OpCode::Equal),
+    OpCode::Greater => simple_instruction(OpCode::Greater),
+    OpCode::GreaterEqual => simple_instruction(OpCode::GreaterEqual),
+    OpCode::Less => simple_instruction(OpCode::Less),
+    OpCode::LessEqual => simple_instruction(OpCode::LessEqual),
+    OpCode::Nil => simple_instruction(OpCode::Nil),
+    OpCode::Boolean(boolean) => simple_instruction(OpCode::Boolean(*boolean)),
+    OpCode::Print => simple_instruction(OpCode::Print),
+    OpCode::Pop => simple_instruction(OpCode::Pop),
+    OpCode::DefineGlobal(slot) => global_instruction(OpCode::DefineGlobal(*slot), *slot, symbols),
+    OpCode::GetGlobal(slot) => global_instruction(OpCode::GetGlobal(*slot), *slot, symbols),
+    OpCode::SetGlobal(slot) => global_instruction(OpCode::SetGlobal(*slot), *slot, symbols),
+    OpCode::GetLocal(slot) => simple_instruction(OpCode::GetLocal(*slot)),
+    OpCode::SetLocal(slot) => simple_instruction(OpCode::SetLocal(*slot)),
+    OpCode::GetUpvalue(index) => simple_instruction(OpCode::GetUpvalue(*index)),
+    OpCode::SetUpvalue(index) => simple_instruction(OpCode::SetUpvalue(*index)),
+    OpCode::CloseUpvalue => simple_instruction(OpCode::CloseUpvalue),
+    OpCode::Closure(index) => closure_instruction(chunk, *index, offset),
+    OpCode::Class(index) => {
+      identifier_instruction(OpCode::Class(*index), chunk.identifiers[*index], symbols)
     }
-    OpCode::Return => simple_instruction(OpCode::Return, offset),
-    OpCode::Negate => simple_instruction(OpCode::Negate, offset),
-    OpCode::Add => simple_instruction(OpCode::Add, offset),
-    OpCode::Subtract => simple_instruction(OpCode::Subtract, offset),
-    OpCode::Multiply => simple_instruction(OpCode::Multiply, offset),
-    OpCode::Divide => simple_instruction(OpCode::Divide, offset),
-    OpCode::Nil => simple_instruction(OpCode::Nil, offset),
-    OpCode::Boolean(boolean) => simple_instruction(OpCode::Boolean(*boolean), offset),
-    OpCode::Print => simple_instruction(OpCode::Print, offset),
-    OpCode::Pop => simple_instruction(OpCode::Pop, offset),
-    OpCode::DefineGlobalVariable(index) => indexed_instruction(
-      OpCode::DefineGlobalVariable(*index),
-      &chunk.constants[*index],
-      offset,
+    OpCode::Method(index) => {
+      identifier_instruction(OpCode::Method(*index), chunk.identifiers[*index], symbols)
+    }
+    OpCode::Inherit => simple_instruction(OpCode::Inherit),
+    OpCode::BuildList(count) => simple_instruction(OpCode::BuildList(*count)),
+    OpCode::BuildMap(count) => simple_instruction(OpCode::BuildMap(*count)),
+    OpCode::Range => simple_instruction(OpCode::Range),
+    OpCode::GetIterator => simple_instruction(OpCode::GetIterator),
+    OpCode::GetIndex => simple_instruction(OpCode::GetIndex),
+    OpCode::SetIndex => simple_instruction(OpCode::SetIndex),
+    OpCode::GetSuper(index) => {
+      identifier_instruction(OpCode::GetSuper(*index), chunk.identifiers[*index], symbols)
+    }
+    OpCode::GetProperty(index) => identifier_instruction(
+      OpCode::GetProperty(*index),
+      chunk.identifiers[*index],
+      symbols,
+    ),
+    OpCode::SetProperty(index) => identifier_instruction(
+      OpCode::SetProperty(*index),
+      chunk.identifiers[*index],
+      symbols,
     ),
-    OpCode::AccessGlobalVariable(variable_name) => {
-      simple_instruction(OpCode::AccessGlobalVariable(variable_name.clone()), offset)
+    OpCode::JumpIfFalse(jump) => jump_instruction(OpCode::JumpIfFalse(*jump), offset + 1 + jump),
+    OpCode::ForIter(jump) => jump_instruction(OpCode::ForIter(*jump), offset + 1 + jump),
+    OpCode::Jump(jump) => jump_instruction(OpCode::Jump(*jump), offset + 1 + jump),
+    OpCode::Loop(jump) => jump_instruction(OpCode::Loop(*jump), offset + 1 - jump),
+    OpCode::Call(argument_count) => simple_instruction(OpCode::Call(*argument_count)),
+  }
+}
+
+fn indexed_instruction(opcode: OpCode, value: &Value) -> String {
+  format!("{:?} {:?}", opcode, value)
+}
+
+fn identifier_instruction(opcode: OpCode, symbol: Symbol, symbols: &SymbolTable) -> String {
+  format!("{:?} {}", opcode, symbols.name(symbol))
+}
+
+fn global_instruction(opcode: OpCode, slot: usize, symbols: &SymbolTable) -> String {
+  format!("{:?} {}", opcode, symbols.global_name(slot))
+}
+
+fn closure_instruction(chunk: &Chunk, index: usize, offset: usize) -> String {
+  let value = &chunk.constants[index];
+
+  let mut listing = format!("{:?} {}", OpCode::Closure(index), value);
+
+  if let Value::Function(function) = value {
+    for upvalue in function.upvalues.iter() {
+      listing.push_str(&format!(
+        "\n{offset:>0width$}   |   {kind} {index}",
+        offset = offset,
+        width = 4,
+        kind = if upvalue.is_local { "local" } else { "upvalue" },
+        index = upvalue.index
+      ));
     }
   }
+
+  listing
 }
 
-fn indexed_instruction(opcode: OpCode, value: &Value, offset: usize) -> usize {
-  println!("{:?} {:?}", opcode, value);
+fn jump_instruction(opcode: OpCode, target: usize) -> String {
+  format!("{:?} -> {}", opcode, target)
+}
 
-  offset + 1
+fn simple_instruction(opcode: OpCode) -> String {
+  format!("{:?}", opcode)
 }
 
-fn simple_instruction(opcode: OpCode, offset: usize) -> usize {
-  println!("{:?}", opcode);
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::compiler::Compiler;
+  use crate::lexer;
+  use crate::vm::Vm;
+
+  fn listing(source_code: &str) -> String {
+    let vm = Vm::new();
+
+    let tokens = lexer::lex(String::from(source_code)).unwrap();
+    let script = Compiler::with_symbols(vm.symbols())
+      .compile(tokens)
+      .unwrap();
 
-  offset + 1
+    let symbols = vm.symbols();
+    let listing = disassemble(&script.chunk, &symbols.borrow());
+
+    listing
+  }
+
+  #[test]
+  fn globals_are_shown_by_name() {
+    let listing = listing("let x = 1; x = len; print x;");
+
+    let instructions: Vec<&str> = listing
+      .lines()
+      .filter(|line| line.contains("Global"))
+      .map(|line| line.rsplit("| ").next().unwrap())
+      .collect();
+
+    assert_eq!(
+      vec![
+        "DefineGlobal(2) x",
+        "GetGlobal(0) len",
+        "SetGlobal(2) x",
+        "GetGlobal(2) x",
+      ],
+      instructions
+    );
+  }
+
+  #[test]
+  fn identifiers_are_shown_by_name() {
+    let test_cases: Vec<(&str, Vec<&str>)> = vec![
+      ("class A {}", vec!["Class(0) A"]),
+      (
+        "let a = nil; a.x = 1; print a.y;",
+        vec!["SetProperty(0) x", "GetProperty(1) y"],
+      ),
+      (
+        "class A { f() {} } class B < A { g() { return super.f; } }",
+        vec!["Method(1) f", "Method(3) g"],
+      ),
+    ];
+
+    for (input, expected_instructions) in test_cases {
+      let listing = listing(input);
+
+      for instruction in expected_instructions {
+        assert!(
+          listing.lines().any(|line| line.ends_with(instruction)),
+          "expected {:?} in\n{}",
+          instruction,
+          listing
+        );
+      }
+    }
+  }
 }
@@ -10,36 +10,47 @@ pub struct LexerError {
 #[derive(Debug)]
 struct Lexer {
   source_code: String,
+  /// Byte offset of the current character.
   position: usize,
-  next_position: usize,
+  /// Line and column of the current character.
   line: usize,
   column: usize,
+  /// '\0' once every character has been read.
   character: char,
   errors: Vec<LexerError>,
 }
 
 impl Lexer {
   pub fn new(source_code: String) -> Lexer {
+    let character = source_code.chars().next().unwrap_or('\0');
+
     let mut lexer = Lexer {
       source_code,
       position: 0,
-      next_position: 0,
-      character: '\0',
+      character,
       line: 1,
       column: 0,
       errors: Vec::new(),
     };
 
-    lexer.read_character();
+    lexer.count_character();
 
     lexer
   }
 
-  pub fn lex(&mut self) -> Result<Vec<(Token, SourceLocation)>, Vec<LexerError>> {
+  pub fn lex(&mut self) -> Result<Vec<(Token, Span)>, Vec<LexerError>> {
     let mut tokens = Vec::new();
 
-    while self.has_characters_to_lex() {
-      tokens.push(self.next_token());
+    loop {
+      let (token, span) = self.next_token();
+
+      let is_eof = token == Token::Eof;
+
+      tokens.push((token, span));
+
+      if is_eof {
+        break;
+      }
     }
 
     if !self.errors.is_empty() {
@@ -49,17 +60,29 @@ impl Lexer {
     Ok(tokens)
   }
 
-  fn has_characters_to_lex(&self) -> bool {
-    self.position <= self.source_code.len()
+  fn is_at_end(&self) -> bool {
+    self.position >= self.source_code.len()
   }
 
+  /// Moves past the current character.
   fn read_character(&mut self) {
-    if self.next_position >= self.source_code.len() {
-      self.character = '\0';
-    } else {
-      self.character = self.source_code.chars().nth(self.next_position).unwrap();
+    if self.is_at_end() {
+      return;
     }
 
+    self.position += self.character.len_utf8();
+
+    self.character = self.source_code[self.position..]
+      .chars()
+      .next()
+      .unwrap_or('\0');
+
+    self.count_character();
+  }
+
+  /// Moves the column to the current character, a newline
+  /// starts the next line at column 0.
+  fn count_character(&mut self) {
     if self.character != '\0' {
       self.column += 1;
     }
@@ -68,30 +91,77 @@ impl Lexer {
       self.line += 1;
       self.column = 0;
     }
+  }
 
-    self.position = self.next_position;
+  fn peek_character(&self) -> char {
+    if self.is_at_end() {
+      return '\0';
+    }
 
-    self.next_position += 1;
+    self.source_code[self.position + self.character.len_utf8()..]
+      .chars()
+      .next()
+      .unwrap_or('\0')
   }
 
-  fn peek_character(&self) -> char {
-    if self.next_position >= self.source_code.len() {
-      '\0'
-    } else {
-      self.source_code.chars().nth(self.next_position).unwrap()
+  fn skip_whitespace_and_comments(&mut self) {
+    loop {
+      match self.character {
+        character if character.is_ascii_whitespace() => self.read_character(),
+        '/' if self.next_character_is('/') => {
+          while self.character != '\n' && self.character != '\0' {
+            self.read_character();
+          }
+        }
+        '/' if self.next_character_is('*') => self.skip_block_comment(),
+        _ => break,
+      }
     }
   }
 
-  fn skip_whitespace(&mut self) {
-    while self.character.is_ascii_whitespace() {
-      self.read_character();
+  /// Skips a `/* */` comment, block comments can be nested.
+  fn skip_block_comment(&mut self) {
+    let comment_starts_at_line = self.line;
+    let comment_starts_at_column = self.column;
+
+    self.read_character(); // advance past /
+    self.read_character(); // advance past *
+
+    let mut depth = 1;
+
+    while depth > 0 {
+      match (self.character, self.peek_character()) {
+        ('\0', _) if self.is_at_end() => {
+          self.error_at(
+            comment_starts_at_line,
+            comment_starts_at_column,
+            String::from("unterminated block comment"),
+          );
+          return;
+        }
+        ('/', '*') => {
+          self.read_character();
+          self.read_character();
+          depth += 1;
+        }
+        ('*', '/') => {
+          self.read_character();
+          self.read_character();
+          depth -= 1;
+        }
+        _ => self.read_character(),
+      }
     }
   }
 
   fn error(&mut self, message: String) {
+    self.error_at(self.line, self.column, message);
+  }
+
+  fn error_at(&mut self, line: usize, column: usize, message: String) {
     self.errors.push(LexerError {
-      line: self.line,
-      column: self.column,
+      line,
+      column,
       message,
     });
   }
@@ -99,145 +169,303 @@ impl Lexer {
   fn read_identifier(&mut self) -> String {
     let identifier_starts_at = self.position;
 
-    while self.character.is_alphabetic() {
+    while is_identifier_character(self.character) {
       self.read_character();
     }
 
-    self
-      .source_code
-      .chars()
-      .skip(identifier_starts_at)
-      .take(self.position - identifier_starts_at)
-      .collect()
+    self.source_code[identifier_starts_at..self.position].to_owned()
   }
 
+  /// Reads decimal, hexadecimal (`0xFF`), binary (`0b1010`) and octal (`0o17`) numbers.
+  ///
+  /// Decimal numbers can have a fraction and an exponent (`1.5e-9`),
+  /// every number can use `_` to separate digits.
   fn read_number(&mut self) -> String {
     let number_starts_at = self.position;
+    let number_starts_at_line = self.line;
+    let number_starts_at_column = self.column;
+
+    let radix = match (self.character, self.peek_character()) {
+      ('0', 'x') => 16,
+      ('0', 'b') => 2,
+      ('0', 'o') => 8,
+      _ => 10,
+    };
 
-    while self.character.is_digit(10) {
-      self.read_character();
-    }
+    if radix == 10 {
+      self.read_decimal_digits();
 
-    if self.character == '.' && self.peek_character().is_digit(10) {
-      self.read_character();
+      if self.character == '.' && self.peek_character().is_ascii_digit() {
+        self.read_character();
+        self.read_decimal_digits();
+      }
+
+      if self.character == 'e' || self.character == 'E' {
+        self.read_character();
+
+        if self.character == '+' || self.character == '-' {
+          self.read_character();
+        }
+
+        self.read_decimal_digits();
+      }
+    } else {
+      self.read_character(); // advance past 0
+      self.read_character(); // advance past the radix
 
-      while self.character.is_digit(10) {
+      // Letters are read as well so invalid digits are reported as part of the number.
+      while self.character.is_ascii_alphanumeric() || self.character == '_' {
         self.read_character();
       }
     }
 
-    self
-      .source_code
-      .chars()
-      .skip(number_starts_at)
-      .take(self.position - number_starts_at)
-      .collect()
+    let number = self.source_code[number_starts_at..self.position].to_owned();
+
+    if let Some(message) = number_literal_error(&number, radix) {
+      self.error_at(number_starts_at_line, number_starts_at_column, message);
+    }
+
+    number
+  }
+
+  fn read_decimal_digits(&mut self) {
+    while self.character.is_ascii_digit() || self.character == '_' {
+      self.read_character();
+    }
   }
 
   fn read_string(&mut self) -> String {
-    let string_starts_at = self.position;
+    self.read_character(); // advance past "
+
+    let mut string = String::new();
+
+    loop {
+      match self.character {
+        '"' => break,
+        '\0' if self.is_at_end() => {
+          self.error(format!(r#"unterminated string: "{}"#, string));
+          return string;
+        }
+        '\\' => {
+          if let Some(character) = self.read_escape_sequence() {
+            string.push(character);
+          }
+        }
+        character => {
+          string.push(character);
+          self.read_character();
+        }
+      }
+    }
 
     self.read_character(); // advance past "
 
-    while self.character != '"' && self.has_characters_to_lex() {
+    string
+  }
+
+  /// Decodes the escape sequence starting at the current `\`.
+  ///
+  /// Errors point at the column of the `\` that starts the sequence.
+  fn read_escape_sequence(&mut self) -> Option<char> {
+    let escape_starts_at_line = self.line;
+    let escape_starts_at_column = self.column;
+
+    self.read_character(); // advance past \
+
+    let escaped_character = match self.character {
+      'n' => '\n',
+      't' => '\t',
+      'r' => '\r',
+      '\\' => '\\',
+      '"' => '"',
+      'u' => return self.read_unicode_escape(escape_starts_at_line, escape_starts_at_column),
+      // Unterminated strings are reported by the caller.
+      '\0' if self.is_at_end() => return None,
+      character => {
+        self.error_at(
+          escape_starts_at_line,
+          escape_starts_at_column,
+          format!("unknown escape sequence: \\{}", character),
+        );
+        self.read_character();
+        return None;
+      }
+    };
+
+    self.read_character();
+
+    Some(escaped_character)
+  }
+
+  /// Decodes a `\u{1F600}` escape, the current character is the `u`.
+  fn read_unicode_escape(&mut self, line: usize, column: usize) -> Option<char> {
+    self.read_character(); // advance past u
+
+    if self.character != '{' {
+      self.error_at(
+        line,
+        column,
+        String::from("malformed unicode escape: expected {"),
+      );
+      return None;
+    }
+
+    self.read_character(); // advance past {
+
+    let mut digits = String::new();
+
+    while self.character.is_ascii_hexdigit() {
+      digits.push(self.character);
       self.read_character();
     }
 
-    let string = self
-      .source_code
-      .chars()
-      .skip(string_starts_at + 1)
-      .take(self.position - string_starts_at - 1)
-      .collect();
+    if self.character != '}' {
+      self.error_at(
+        line,
+        column,
+        String::from("malformed unicode escape: expected }"),
+      );
+      return None;
+    }
 
-    if self.character != '"' {
-      self.read_character(); // advance past "
-      self.error(format!(r#"unterminated string: "{}"#, string))
-    } else {
-      self.read_character(); // advance past "
+    self.read_character(); // advance past }
+
+    if digits.is_empty() || digits.len() > 6 {
+      self.error_at(
+        line,
+        column,
+        format!(
+          "malformed unicode escape: expected 1 to 6 hex digits, got {}",
+          digits.len()
+        ),
+      );
+      return None;
     }
 
-    string
+    let code_point = u32::from_str_radix(&digits, 16).expect("digits should be hexadecimal");
+
+    match char::from_u32(code_point) {
+      Some(character) => Some(character),
+      None => {
+        self.error_at(
+          line,
+          column,
+          format!("invalid unicode code point: {}", digits),
+        );
+        None
+      }
+    }
   }
 
-  fn next_character_is(&self, expected_character: char) -> bool {
-    if self.next_position >= self.source_code.len() {
-      return false;
+  /// Reads a `r"..."` string, backslashes in raw strings are kept as they are.
+  fn read_raw_string(&mut self) -> String {
+    self.read_character(); // advance past r
+    self.read_character(); // advance past "
+
+    let mut string = String::new();
+
+    while self.character != '"' {
+      if self.character == '\0' && self.is_at_end() {
+        self.error(format!(r#"unterminated string: r"{}"#, string));
+        return string;
+      }
+
+      string.push(self.character);
+      self.read_character();
     }
 
-    let character = self.source_code.chars().nth(self.next_position).unwrap();
+    self.read_character(); // advance past "
 
-    character == expected_character
+    string
   }
 
-  fn source_location(&self) -> SourceLocation {
-    // TODO: this is broken, column is always the position of
-    // the last character of the current lexeme
-    // but it should be position of the the first.
-    SourceLocation {
-      line: self.line,
-      column: self.column,
-    }
+  fn next_character_is(&self, expected_character: char) -> bool {
+    self.peek_character() == expected_character
   }
 
-  fn next_token(&mut self) -> (Token, SourceLocation) {
-    self.skip_whitespace();
+  fn next_token(&mut self) -> (Token, Span) {
+    self.skip_whitespace_and_comments();
+
+    let start = self.position;
+    let line = self.line;
+    let column = self.column;
+
+    let token = self.read_token();
+
+    let span = Span {
+      start,
+      end: self.position,
+      line,
+      column,
+    };
+
+    (token, span)
+  }
 
+  fn read_token(&mut self) -> Token {
     let token = match self.character {
-      ';' => (Token::Semicolon, self.source_location()),
-      '(' => (Token::LeftParen, self.source_location()),
-      ')' => (Token::RightParen, self.source_location()),
-      ',' => (Token::Comma, self.source_location()),
-      '+' => (Token::Plus, self.source_location()),
-      '-' => (Token::Minus, self.source_location()),
-      '{' => (Token::LeftBrace, self.source_location()),
-      '}' => (Token::RightBrace, self.source_location()),
-      '[' => (Token::LeftBracket, self.source_location()),
-      ']' => (Token::RightBracket, self.source_location()),
-      '*' => (Token::Star, self.source_location()),
-      '/' => (Token::Slash, self.source_location()),
+      ';' => Token::Semicolon,
+      '(' => Token::LeftParen,
+      ')' => Token::RightParen,
+      ',' => Token::Comma,
+      '.' => {
+        if self.next_character_is('.') {
+          self.read_character();
+          Token::DotDot
+        } else {
+          Token::Dot
+        }
+      }
+      '+' => Token::Plus,
+      '-' => Token::Minus,
+      '{' => Token::LeftBrace,
+      '}' => Token::RightBrace,
+      '[' => Token::LeftBracket,
+      ']' => Token::RightBracket,
+      ':' => Token::Colon,
+      '*' => Token::Star,
+      '/' => Token::Slash,
       '>' => {
         if self.next_character_is('=') {
           self.read_character();
-          (Token::GreaterThanOrEqual, self.source_location())
+          Token::GreaterThanOrEqual
         } else {
-          (Token::GreaterThan, self.source_location())
+          Token::GreaterThan
         }
       }
       '<' => {
         if self.next_character_is('=') {
           self.read_character();
-          (Token::LessThanOrEqual, self.source_location())
+          Token::LessThanOrEqual
         } else {
-          (Token::LessThan, self.source_location())
+          Token::LessThan
         }
       }
       '!' => {
         if self.next_character_is('=') {
           self.read_character();
-          (Token::NotEqual, self.source_location())
+          Token::NotEqual
         } else {
-          (Token::Bang, self.source_location())
+          Token::Bang
         }
       }
       '=' => {
         if self.next_character_is('=') {
           self.read_character();
-          (Token::Equal, self.source_location())
+          Token::Equal
         } else {
-          (Token::Assign, self.source_location())
+          Token::Assign
         }
       }
-      '\0' => (Token::Eof, self.source_location()),
-      '"' => return (Token::String(self.read_string()), self.source_location()),
-      character if character.is_alphabetic() => {
+      '\0' if self.is_at_end() => return Token::Eof,
+      '"' => return Token::String(self.read_string()),
+      'r' if self.next_character_is('"') => return Token::String(self.read_raw_string()),
+      character if is_identifier_start(character) => {
         let identifier = self.read_identifier();
-        return (lookup_identifier(identifier), self.source_location());
-      }
-      character if character.is_digit(10) => {
-        return (Token::Number(self.read_number()), self.source_location())
+        return lookup_identifier(identifier);
       }
-      character => (Token::Illegal(character), self.source_location()),
+      character if character.is_ascii_digit() => return Token::Number(self.read_number()),
+      character => Token::Illegal(character),
     };
 
     self.read_character();
@@ -246,7 +474,63 @@ impl Lexer {
   }
 }
 
-pub fn lex(source_code: String) -> Result<Vec<(Token, SourceLocation)>, Vec<LexerError>> {
+/// Identifiers start with a letter or an underscore.
+fn is_identifier_start(character: char) -> bool {
+  character.is_alphabetic() || character == '_'
+}
+
+/// Digits can appear in identifiers after the first character.
+fn is_identifier_character(character: char) -> bool {
+  character.is_alphanumeric() || character == '_'
+}
+
+/// Returns why `number` is not a valid number literal in base `radix`.
+fn number_literal_error(number: &str, radix: u32) -> Option<String> {
+  let digits = if radix == 10 { number } else { &number[2..] };
+
+  if digits.chars().all(|character| character == '_') {
+    return Some(format!("missing digits in number literal: {}", number));
+  }
+
+  if radix != 10 {
+    if let Some(digit) = digits
+      .chars()
+      .find(|character| *character != '_' && !character.is_digit(radix))
+    {
+      return Some(format!(
+        "invalid digit {} in number literal: {}",
+        digit, number
+      ));
+    }
+  }
+
+  if radix == 10 && number.ends_with(['e', 'E', '+', '-']) {
+    return Some(format!("missing exponent in number literal: {}", number));
+  }
+
+  // Underscores can only separate digits.
+  let characters: Vec<char> = number.chars().collect();
+
+  let has_misplaced_underscore = characters.iter().enumerate().any(|(i, character)| {
+    *character == '_'
+      && !(i > 0
+        && characters[i - 1].is_digit(radix)
+        && characters
+          .get(i + 1)
+          .is_some_and(|next| next.is_digit(radix)))
+  });
+
+  if has_misplaced_underscore {
+    return Some(format!(
+      "misplaced underscore in number literal: {}",
+      number
+    ));
+  }
+
+  None
+}
+
+pub fn lex(source_code: String) -> Result<Vec<(Token, Span)>, Vec<LexerError>> {
   Lexer::new(source_code).lex()
 }
 
@@ -376,6 +660,7 @@ let b = 20",
       ("(", vec![Token::LeftParen, Token::Eof]),
       (")", vec![Token::RightParen, Token::Eof]),
       (",", vec![Token::Comma, Token::Eof]),
+      (".", vec![Token::Dot, Token::Eof]),
       ("+", vec![Token::Plus, Token::Eof]),
       ("-", vec![Token::Minus, Token::Eof]),
       ("!", vec![Token::Bang, Token::Eof]),
@@ -387,6 +672,7 @@ let b = 20",
       ("<", vec![Token::LessThan, Token::Eof]),
       ("[", vec![Token::LeftBracket, Token::Eof]),
       ("]", vec![Token::RightBracket, Token::Eof]),
+      (":", vec![Token::Colon, Token::Eof]),
     ];
 
     for (input, expected_tokens) in test_cases {
@@ -410,6 +696,7 @@ let b = 20",
       ("==", vec![Token::Equal, Token::Eof]),
       ("!=", vec![Token::NotEqual, Token::Eof]),
       (">=", vec![Token::GreaterThanOrEqual, Token::Eof]),
+      ("..", vec![Token::DotDot, Token::Eof]),
       ("<=", vec![Token::LessThanOrEqual, Token::Eof]),
     ];
 
@@ -445,6 +732,47 @@ let b = 20",
       ),
       ("x", vec![Token::Identifier(String::from("x")), Token::Eof]),
       ("y", vec![Token::Identifier(String::from("y")), Token::Eof]),
+      (
+        "user_id",
+        vec![Token::Identifier(String::from("user_id")), Token::Eof],
+      ),
+      (
+        "x2",
+        vec![Token::Identifier(String::from("x2")), Token::Eof],
+      ),
+      (
+        "_private",
+        vec![Token::Identifier(String::from("_private")), Token::Eof],
+      ),
+      ("_", vec![Token::Identifier(String::from("_")), Token::Eof]),
+      (
+        "a1_b2__c3",
+        vec![Token::Identifier(String::from("a1_b2__c3")), Token::Eof],
+      ),
+      (
+        "r2d2",
+        vec![Token::Identifier(String::from("r2d2")), Token::Eof],
+      ),
+      (
+        "2x",
+        vec![
+          Token::Number(String::from("2")),
+          Token::Identifier(String::from("x")),
+          Token::Eof,
+        ],
+      ),
+      (
+        "let_",
+        vec![Token::Identifier(String::from("let_")), Token::Eof],
+      ),
+      (
+        "classes",
+        vec![Token::Identifier(String::from("classes")), Token::Eof],
+      ),
+      (
+        "for1",
+        vec![Token::Identifier(String::from("for1")), Token::Eof],
+      ),
     ];
 
     for (input, expected_tokens) in test_cases {
@@ -472,6 +800,18 @@ let b = 20",
       ("false", vec![Token::False, Token::Eof]),
       ("if", vec![Token::If, Token::Eof]),
       ("else", vec![Token::Else, Token::Eof]),
+      ("while", vec![Token::While, Token::Eof]),
+      ("class", vec![Token::Class, Token::Eof]),
+      ("this", vec![Token::This, Token::Eof]),
+      ("super", vec![Token::Super, Token::Eof]),
+      ("and", vec![Token::And, Token::Eof]),
+      ("or", vec![Token::Or, Token::Eof]),
+      ("for", vec![Token::For, Token::Eof]),
+      ("in", vec![Token::In, Token::Eof]),
+      ("break", vec![Token::Break, Token::Eof]),
+      ("continue", vec![Token::Continue, Token::Eof]),
+      ("print", vec![Token::Print, Token::Eof]),
+      ("nil", vec![Token::Nil, Token::Eof]),
       (
         "if(x > 3) {}",
         vec![
@@ -634,4 +974,632 @@ let b = 20",
       assert_eq!(Err(expected_errors), lexer.lex());
     }
   }
+
+  #[test]
+  fn comments() {
+    let test_cases: Vec<(&str, Vec<Token>)> = vec![
+      ("// a comment", vec![Token::Eof]),
+      ("//", vec![Token::Eof]),
+      (
+        "1 / 2 // divides",
+        vec![
+          Token::Number(String::from("1")),
+          Token::Slash,
+          Token::Number(String::from("2")),
+          Token::Eof,
+        ],
+      ),
+      (
+        "let a = 1; // first\nlet b = 2;",
+        vec![
+          Token::Let,
+          Token::Identifier(String::from("a")),
+          Token::Assign,
+          Token::Number(String::from("1")),
+          Token::Semicolon,
+          Token::Let,
+          Token::Identifier(String::from("b")),
+          Token::Assign,
+          Token::Number(String::from("2")),
+          Token::Semicolon,
+          Token::Eof,
+        ],
+      ),
+      ("/* a comment */", vec![Token::Eof]),
+      ("/**/", vec![Token::Eof]),
+      (
+        "1 /* in between */ + 2",
+        vec![
+          Token::Number(String::from("1")),
+          Token::Plus,
+          Token::Number(String::from("2")),
+          Token::Eof,
+        ],
+      ),
+      (
+        "/* spans\nmultiple\nlines */ a",
+        vec![Token::Identifier(String::from("a")), Token::Eof],
+      ),
+      (
+        "/* outer /* inner */ still a comment */ a",
+        vec![Token::Identifier(String::from("a")), Token::Eof],
+      ),
+      ("/* // line comments are ignored */", vec![Token::Eof]),
+      ("// /* block comments are ignored", vec![Token::Eof]),
+      ("/* a */ /* b */", vec![Token::Eof]),
+    ];
+
+    for (input, expected_tokens) in test_cases {
+      let mut lexer = Lexer::new(String::from(input));
+
+      let tokens: Vec<Token> = lexer
+        .lex()
+        .unwrap()
+        .into_iter()
+        .map(|(token, _location)| token)
+        .collect();
+
+      assert_eq!(expected_tokens, tokens);
+    }
+  }
+
+  #[test]
+  fn comments_keep_track_of_line_and_column() {
+    let test_cases: Vec<(&str, usize, usize)> = vec![
+      ("// comment\na", 2, 1),
+      ("/* one\ntwo\nthree */ a", 3, 10),
+      ("/* /* nested\n */ */ ab", 2, 8),
+    ];
+
+    for (input, expected_line, expected_column) in test_cases {
+      let mut lexer = Lexer::new(String::from(input));
+
+      let tokens = lexer.lex().unwrap();
+
+      let (_token, location) = &tokens[0];
+
+      assert_eq!(expected_line, location.line);
+      assert_eq!(expected_column, location.column);
+    }
+  }
+
+  #[test]
+  fn unterminated_block_comments() {
+    let test_cases: Vec<(&str, Vec<LexerError>)> = vec![
+      (
+        "/* never ends",
+        vec![LexerError {
+          line: 1,
+          column: 1,
+          message: String::from("unterminated block comment"),
+        }],
+      ),
+      (
+        "a\n  /* outer /* inner */",
+        vec![LexerError {
+          line: 2,
+          column: 3,
+          message: String::from("unterminated block comment"),
+        }],
+      ),
+    ];
+
+    for (input, expected_errors) in test_cases {
+      let mut lexer = Lexer::new(String::from(input));
+
+      assert_eq!(Err(expected_errors), lexer.lex());
+    }
+  }
+
+  #[test]
+  fn string_escape_sequences() {
+    let test_cases: Vec<(&str, &str)> = vec![
+      (r#""a\nb""#, "a\nb"),
+      (r#""a\tb""#, "a\tb"),
+      (r#""a\rb""#, "a\rb"),
+      (r#""a\\b""#, "a\\b"),
+      (r#""say \"hi\"""#, "say \"hi\""),
+      (r#""\u{41}""#, "A"),
+      (r#""\u{e9}""#, "\u{e9}"),
+      (r#""\u{1F600}""#, "\u{1F600}"),
+      (r#""\\n""#, "\\n"),
+      (r#""\n\t\\""#, "\n\t\\"),
+    ];
+
+    for (input, expected_string) in test_cases {
+      let mut lexer = Lexer::new(String::from(input));
+
+      let tokens: Vec<Token> = lexer
+        .lex()
+        .unwrap()
+        .into_iter()
+        .map(|(token, _location)| token)
+        .collect();
+
+      assert_eq!(
+        vec![Token::String(String::from(expected_string)), Token::Eof],
+        tokens
+      );
+    }
+  }
+
+  #[test]
+  fn raw_strings() {
+    let test_cases: Vec<(&str, Vec<Token>)> = vec![
+      (
+        r#"r"a\nb""#,
+        vec![Token::String(String::from(r"a\nb")), Token::Eof],
+      ),
+      (
+        r#"r"\u{41} \q""#,
+        vec![Token::String(String::from(r"\u{41} \q")), Token::Eof],
+      ),
+      (r#"r"""#, vec![Token::String(String::new()), Token::Eof]),
+      ("r", vec![Token::Identifier(String::from("r")), Token::Eof]),
+      (
+        r#"r "a""#,
+        vec![
+          Token::Identifier(String::from("r")),
+          Token::String(String::from("a")),
+          Token::Eof,
+        ],
+      ),
+    ];
+
+    for (input, expected_tokens) in test_cases {
+      let mut lexer = Lexer::new(String::from(input));
+
+      let tokens: Vec<Token> = lexer
+        .lex()
+        .unwrap()
+        .into_iter()
+        .map(|(token, _location)| token)
+        .collect();
+
+      assert_eq!(expected_tokens, tokens);
+    }
+  }
+
+  #[test]
+  fn invalid_escape_sequences() {
+    let test_cases: Vec<(&str, Vec<LexerError>)> = vec![
+      (
+        r#""ab\q""#,
+        vec![LexerError {
+          line: 1,
+          column: 4,
+          message: String::from(r"unknown escape sequence: \q"),
+        }],
+      ),
+      (
+        "\"a\n  \\x\"",
+        vec![LexerError {
+          line: 2,
+          column: 3,
+          message: String::from(r"unknown escape sequence: \x"),
+        }],
+      ),
+      (
+        r#""\u41""#,
+        vec![LexerError {
+          line: 1,
+          column: 2,
+          message: String::from("malformed unicode escape: expected {"),
+        }],
+      ),
+      (
+        r#""a\u{41""#,
+        vec![LexerError {
+          line: 1,
+          column: 3,
+          message: String::from("malformed unicode escape: expected }"),
+        }],
+      ),
+      (
+        r#""\u{4g}""#,
+        vec![LexerError {
+          line: 1,
+          column: 2,
+          message: String::from("malformed unicode escape: expected }"),
+        }],
+      ),
+      (
+        r#""\u{}""#,
+        vec![LexerError {
+          line: 1,
+          column: 2,
+          message: String::from("malformed unicode escape: expected 1 to 6 hex digits, got 0"),
+        }],
+      ),
+      (
+        r#""\u{1234567}""#,
+        vec![LexerError {
+          line: 1,
+          column: 2,
+          message: String::from("malformed unicode escape: expected 1 to 6 hex digits, got 7"),
+        }],
+      ),
+      (
+        r#""\u{D800}""#,
+        vec![LexerError {
+          line: 1,
+          column: 2,
+          message: String::from("invalid unicode code point: D800"),
+        }],
+      ),
+      (
+        r#""\q \w""#,
+        vec![
+          LexerError {
+            line: 1,
+            column: 2,
+            message: String::from(r"unknown escape sequence: \q"),
+          },
+          LexerError {
+            line: 1,
+            column: 5,
+            message: String::from(r"unknown escape sequence: \w"),
+          },
+        ],
+      ),
+      (
+        r#"r"10"#,
+        vec![LexerError {
+          line: 1,
+          column: 4,
+          message: String::from(r#"unterminated string: r"10"#),
+        }],
+      ),
+    ];
+
+    for (input, expected_errors) in test_cases {
+      let mut lexer = Lexer::new(String::from(input));
+
+      assert_eq!(Err(expected_errors), lexer.lex());
+    }
+  }
+
+  #[test]
+  fn number_literals() {
+    let test_cases: Vec<&str> = vec![
+      "0xFF",
+      "0xff",
+      "0x0",
+      "0b1010",
+      "0o17",
+      "1_000_000",
+      "0xFF_FF",
+      "0b1010_1010",
+      "1_000.000_1",
+      "1e9",
+      "1E9",
+      "1e-9",
+      "1e+9",
+      "1.5e3",
+      "1e1_0",
+    ];
+
+    for input in test_cases {
+      let mut lexer = Lexer::new(String::from(input));
+
+      let tokens: Vec<Token> = lexer
+        .lex()
+        .unwrap()
+        .into_iter()
+        .map(|(token, _location)| token)
+        .collect();
+
+      assert_eq!(vec![Token::Number(String::from(input)), Token::Eof], tokens);
+    }
+  }
+
+  #[test]
+  fn malformed_number_literals() {
+    let test_cases: Vec<(&str, usize, &str)> = vec![
+      ("0x", 1, "missing digits in number literal: 0x"),
+      ("0b__", 1, "missing digits in number literal: 0b__"),
+      ("0b102", 1, "invalid digit 2 in number literal: 0b102"),
+      ("0o8", 1, "invalid digit 8 in number literal: 0o8"),
+      ("0xFG", 1, "invalid digit G in number literal: 0xFG"),
+      ("1e", 1, "missing exponent in number literal: 1e"),
+      ("1e-", 1, "missing exponent in number literal: 1e-"),
+      ("2.5E+", 1, "missing exponent in number literal: 2.5E+"),
+      ("1_", 1, "misplaced underscore in number literal: 1_"),
+      ("1__0", 1, "misplaced underscore in number literal: 1__0"),
+      ("0x_FF", 1, "misplaced underscore in number literal: 0x_FF"),
+      ("1e_5", 1, "misplaced underscore in number literal: 1e_5"),
+      ("1_.5", 1, "misplaced underscore in number literal: 1_.5"),
+      ("let a = 0b2;", 9, "invalid digit 2 in number literal: 0b2"),
+    ];
+
+    for (input, expected_column, expected_message) in test_cases {
+      let mut lexer = Lexer::new(String::from(input));
+
+      assert_eq!(
+        Err(vec![LexerError {
+          line: 1,
+          column: expected_column,
+          message: String::from(expected_message),
+        }]),
+        lexer.lex()
+      );
+    }
+  }
+
+  /// The source text of keyword tokens.
+  ///
+  /// The match is exhaustive so new tokens have to be classified here.
+  fn keyword_lexeme(token: &Token) -> Option<&'static str> {
+    match token {
+      Token::And => Some("and"),
+      Token::Break => Some("break"),
+      Token::Class => Some("class"),
+      Token::Continue => Some("continue"),
+      Token::Else => Some("else"),
+      Token::False => Some("false"),
+      Token::For => Some("for"),
+      Token::Function => Some("fn"),
+      Token::If => Some("if"),
+      Token::In => Some("in"),
+      Token::Nil => Some("nil"),
+      Token::Or => Some("or"),
+      Token::Print => Some("print"),
+      Token::Return => Some("return"),
+      Token::Super => Some("super"),
+      Token::This => Some("this"),
+      Token::True => Some("true"),
+      Token::Let => Some("let"),
+      Token::While => Some("while"),
+      Token::LeftParen
+      | Token::RightParen
+      | Token::LeftBrace
+      | Token::RightBrace
+      | Token::Comma
+      | Token::Colon
+      | Token::Dot
+      | Token::DotDot
+      | Token::Minus
+      | Token::Plus
+      | Token::Semicolon
+      | Token::Slash
+      | Token::Star
+      | Token::Bang
+      | Token::BangEqual
+      | Token::Equal
+      | Token::EqualEqual
+      | Token::GreaterThan
+      | Token::GreaterThanOrEqual
+      | Token::LessThan
+      | Token::LessThanOrEqual
+      | Token::NotEqual
+      | Token::Assign
+      | Token::LeftBracket
+      | Token::RightBracket
+      | Token::Identifier(_)
+      | Token::String(_)
+      | Token::Number(_)
+      | Token::Illegal(_)
+      | Token::Eof => None,
+    }
+  }
+
+  #[test]
+  fn every_keyword_can_be_lexed() {
+    let keywords = vec![
+      Token::And,
+      Token::Break,
+      Token::Class,
+      Token::Continue,
+      Token::Else,
+      Token::False,
+      Token::For,
+      Token::Function,
+      Token::If,
+      Token::In,
+      Token::Nil,
+      Token::Or,
+      Token::Print,
+      Token::Return,
+      Token::Super,
+      Token::This,
+      Token::True,
+      Token::Let,
+      Token::While,
+    ];
+
+    for keyword in keywords {
+      let lexeme = keyword_lexeme(&keyword).unwrap();
+
+      let tokens: Vec<Token> = Lexer::new(String::from(lexeme))
+        .lex()
+        .unwrap()
+        .into_iter()
+        .map(|(token, _location)| token)
+        .collect();
+
+      assert_eq!(vec![keyword, Token::Eof], tokens);
+
+      // Keywords followed by identifier characters are identifiers.
+      for suffix in ["_", "1", "s"] {
+        let identifier = format!("{}{}", lexeme, suffix);
+
+        let tokens: Vec<Token> = Lexer::new(identifier.clone())
+          .lex()
+          .unwrap()
+          .into_iter()
+          .map(|(token, _location)| token)
+          .collect();
+
+        assert_eq!(vec![Token::Identifier(identifier), Token::Eof], tokens);
+      }
+    }
+  }
+
+  #[test]
+  fn spans_point_at_the_first_character_of_tokens() {
+    let test_cases: Vec<(&str, Vec<(Token, Span)>)> = vec![
+      (
+        "let a = 10;",
+        vec![
+          (
+            Token::Let,
+            Span {
+              start: 0,
+              end: 3,
+              line: 1,
+              column: 1,
+            },
+          ),
+          (
+            Token::Identifier(String::from("a")),
+            Span {
+              start: 4,
+              end: 5,
+              line: 1,
+              column: 5,
+            },
+          ),
+          (
+            Token::Assign,
+            Span {
+              start: 6,
+              end: 7,
+              line: 1,
+              column: 7,
+            },
+          ),
+          (
+            Token::Number(String::from("10")),
+            Span {
+              start: 8,
+              end: 10,
+              line: 1,
+              column: 9,
+            },
+          ),
+          (
+            Token::Semicolon,
+            Span {
+              start: 10,
+              end: 11,
+              line: 1,
+              column: 11,
+            },
+          ),
+          (
+            Token::Eof,
+            Span {
+              start: 11,
+              end: 11,
+              line: 1,
+              column: 11,
+            },
+          ),
+        ],
+      ),
+      (
+        "a >= b\n  \"s\"",
+        vec![
+          (
+            Token::Identifier(String::from("a")),
+            Span {
+              start: 0,
+              end: 1,
+              line: 1,
+              column: 1,
+            },
+          ),
+          (
+            Token::GreaterThanOrEqual,
+            Span {
+              start: 2,
+              end: 4,
+              line: 1,
+              column: 3,
+            },
+          ),
+          (
+            Token::Identifier(String::from("b")),
+            Span {
+              start: 5,
+              end: 6,
+              line: 1,
+              column: 6,
+            },
+          ),
+          (
+            Token::String(String::from("s")),
+            Span {
+              start: 9,
+              end: 12,
+              line: 2,
+              column: 3,
+            },
+          ),
+          (
+            Token::Eof,
+            Span {
+              start: 12,
+              end: 12,
+              line: 2,
+              column: 5,
+            },
+          ),
+        ],
+      ),
+      // Offsets count bytes, columns count characters.
+      (
+        "\"é\" ü",
+        vec![
+          (
+            Token::String(String::from("é")),
+            Span {
+              start: 0,
+              end: 4,
+              line: 1,
+              column: 1,
+            },
+          ),
+          (
+            Token::Identifier(String::from("ü")),
+            Span {
+              start: 5,
+              end: 7,
+              line: 1,
+              column: 5,
+            },
+          ),
+          (
+            Token::Eof,
+            Span {
+              start: 7,
+              end: 7,
+              line: 1,
+              column: 5,
+            },
+          ),
+        ],
+      ),
+    ];
+
+    for (input, expected_tokens) in test_cases {
+      let mut lexer = Lexer::new(String::from(input));
+
+      assert_eq!(Ok(expected_tokens), lexer.lex());
+    }
+  }
+
+  #[test]
+  fn lexes_large_scripts() {
+    let source_code = "let value_1 = (10 + 20) * 3; // comment\n".repeat(50_000);
+
+    let tokens = Lexer::new(source_code).lex().unwrap();
+
+    assert_eq!(50_000 * 11 + 1, tokens.len());
+
+    let (_token, span) = &tokens[tokens.len() - 2];
+
+    assert_eq!(50_000, span.line);
+    assert_eq!(28, span.column);
+  }
 }
@@ -0,0 +1,12 @@
+pub mod chunk;
+pub mod compiler;
+pub mod disassembler;
+pub mod lexer;
+#[cfg(feature = "nan-boxing")]
+pub mod nan_box;
+pub mod native;
+pub mod stack;
+pub mod symbol;
+pub mod token;
+pub mod value;
+pub mod vm;
@@ -1,19 +1,12 @@
-pub mod chunk;
-pub mod compiler;
-pub mod disassembler;
-pub mod lexer;
-pub mod token;
-pub mod value;
-pub mod vm;
-
 use std::io::{self, Write};
 
-use compiler::Compiler;
-use vm::{InterpretResult, Vm};
+use bytecode_vm::compiler::Compiler;
+use bytecode_vm::lexer;
+use bytecode_vm::vm::{InterpretResult, Vm};
 
 fn main() {
-  let mut compiler = Compiler::new();
   let mut vm = Vm::new();
+  let mut compiler = Compiler::with_symbols(vm.symbols());
 
   loop {
     print!("> ");
@@ -28,11 +21,14 @@ fn main() {
 
     match lexer::lex(buffer) {
       Err(errors) => println!("{:?}", errors),
-      Ok(tokens) => {
-        if let InterpretResult::Ok(Some(result)) = vm.run(compiler.compile(tokens)) {
-          println!("{:?}", result);
+      Ok(tokens) => match compiler.compile(tokens) {
+        Err(errors) => println!("{:?}", errors),
+        Ok(script) => {
+          if let InterpretResult::RuntimeError(error) = vm.run(script) {
+            println!("{}", error);
+          }
         }
-      }
+      },
     }
   }
 }
@@ -0,0 +1,297 @@
+/// NaN boxing packs a value into the 64 bits of a double.
+///
+/// Any double that isn't a quiet NaN is a number. Quiet NaNs have
+/// 51 bits nobody looks at, which are enough for a few tags for
+/// `nil` and booleans, or a 48 bit pointer. Objects are stored as
+/// the pointer of their own `Rc` with the kind of object in its low
+/// bits, which are always zero because `Rc`s are 8 byte aligned.
+/// Strings and ranges don't fit in a pointer so they are boxed in
+/// an `Rc<Value>`, and so are functions, which are only read from
+/// the constants and rarely reach the stack.
+use crate::value::Value;
+
+use std::fmt;
+use std::mem::{self, ManuallyDrop};
+use std::rc::Rc;
+
+#[cfg(not(target_pointer_width = "64"))]
+compile_error!("the nan-boxing feature needs 64 bit pointers");
+
+const SIGN_BIT: u64 = 0x8000_0000_0000_0000;
+const QUIET_NAN: u64 = 0x7ffc_0000_0000_0000;
+
+const NIL: u64 = QUIET_NAN | 1;
+const FALSE: u64 = QUIET_NAN | 2;
+const TRUE: u64 = QUIET_NAN | 3;
+const OBJECT: u64 = SIGN_BIT | QUIET_NAN;
+const BOXED: u64 = QUIET_NAN | (1 << 48);
+
+const POINTER: u64 = 0x0000_ffff_ffff_fff8;
+const KIND: u64 = 0b111;
+
+const LIST: u64 = 0;
+const MAP: u64 = 1;
+const CLOSURE: u64 = 2;
+const INSTANCE: u64 = 3;
+const BOUND_METHOD: u64 = 4;
+const NATIVE_FUNCTION: u64 = 5;
+const CLASS: u64 = 6;
+const ITERATOR: u64 = 7;
+
+/// NaNs produced by arithmetic can have any payload, they are all
+/// stored as this one so they can't be mistaken for a tagged value.
+const CANONICAL_NAN: u64 = 0x7ff8_0000_0000_0000;
+
+pub struct NanBox(u64);
+
+impl NanBox {
+  fn pointer<T>(tag: u64, pointer: *const T) -> Self {
+    let pointer = pointer as u64;
+
+    assert!(
+      pointer & !POINTER == 0,
+      "pointer {:#x} doesn't fit in a nan box",
+      pointer
+    );
+
+    NanBox(tag | pointer)
+  }
+
+  fn is_number(&self) -> bool {
+    self.0 & QUIET_NAN != QUIET_NAN
+  }
+
+  fn is_object(&self) -> bool {
+    self.0 & OBJECT == OBJECT
+  }
+
+  fn is_boxed(&self) -> bool {
+    self.0 & (OBJECT | BOXED) == BOXED
+  }
+
+  /// Returns the number without unpacking anything else.
+  pub fn as_number(&self) -> Option<f64> {
+    if self.is_number() {
+      Some(f64::from_bits(self.0))
+    } else {
+      None
+    }
+  }
+
+  pub fn to_value(&self) -> Value {
+    match self.as_number() {
+      Some(number) => Value::Number(number),
+      None => Value::from(self.clone()),
+    }
+  }
+}
+
+/// Rebuilds the value packed into `bits`, which takes over
+/// the strong reference held by the box of an object.
+///
+/// # Safety
+///
+/// `bits` must come from a box that is not used or dropped afterwards,
+/// unless the value is never dropped either.
+unsafe fn take(bits: u64) -> Value {
+  let nan_box = ManuallyDrop::new(NanBox(bits));
+
+  if let Some(number) = nan_box.as_number() {
+    return Value::Number(number);
+  }
+
+  let pointer = bits & POINTER;
+
+  if nan_box.is_object() {
+    return match bits & KIND {
+      LIST => Value::List(Rc::from_raw(pointer as *const _)),
+      MAP => Value::Map(Rc::from_raw(pointer as *const _)),
+      CLOSURE => Value::Closure(Rc::from_raw(pointer as *const _)),
+      INSTANCE => Value::Instance(Rc::from_raw(pointer as *const _)),
+      BOUND_METHOD => Value::BoundMethod(Rc::from_raw(pointer as *const _)),
+      NATIVE_FUNCTION => Value::NativeFunction(Rc::from_raw(pointer as *const _)),
+      CLASS => Value::Class(Rc::from_raw(pointer as *const _)),
+      _ => Value::Iterator(Rc::from_raw(pointer as *const _)),
+    };
+  }
+
+  if nan_box.is_boxed() {
+    let value = Rc::from_raw(pointer as *const Value);
+    return Rc::try_unwrap(value).unwrap_or_else(|value| (*value).clone());
+  }
+
+  match bits {
+    NIL => Value::Nil,
+    FALSE => Value::Boolean(false),
+    TRUE => Value::Boolean(true),
+    _ => unreachable!("{:#x} isn't a nan box", bits),
+  }
+}
+
+impl From<Value> for NanBox {
+  fn from(value: Value) -> Self {
+    match value {
+      Value::Number(number) if number.is_nan() => NanBox(CANONICAL_NAN),
+      Value::Number(number) => NanBox(number.to_bits()),
+      Value::Nil => NanBox(NIL),
+      Value::Boolean(false) => NanBox(FALSE),
+      Value::Boolean(true) => NanBox(TRUE),
+      Value::List(list) => NanBox::pointer(OBJECT | LIST, Rc::into_raw(list)),
+      Value::Map(map) => NanBox::pointer(OBJECT | MAP, Rc::into_raw(map)),
+      Value::Closure(closure) => NanBox::pointer(OBJECT | CLOSURE, Rc::into_raw(closure)),
+      Value::Instance(instance) => NanBox::pointer(OBJECT | INSTANCE, Rc::into_raw(instance)),
+      Value::BoundMethod(method) => NanBox::pointer(OBJECT | BOUND_METHOD, Rc::into_raw(method)),
+      Value::NativeFunction(function) => {
+        NanBox::pointer(OBJECT | NATIVE_FUNCTION, Rc::into_raw(function))
+      }
+      Value::Class(class) => NanBox::pointer(OBJECT | CLASS, Rc::into_raw(class)),
+      Value::Iterator(iterator) => NanBox::pointer(OBJECT | ITERATOR, Rc::into_raw(iterator)),
+      value => NanBox::pointer(BOXED, Rc::into_raw(Rc::new(value))),
+    }
+  }
+}
+
+impl From<NanBox> for Value {
+  fn from(nan_box: NanBox) -> Self {
+    if let Some(number) = nan_box.as_number() {
+      return Value::Number(number);
+    }
+
+    let bits = nan_box.0;
+    mem::forget(nan_box);
+
+    // SAFETY: the box was just forgotten, its reference moves into the value.
+    unsafe { take(bits) }
+  }
+}
+
+impl Clone for NanBox {
+  fn clone(&self) -> Self {
+    if self.is_object() {
+      // SAFETY: the value borrows the reference of this box and is never
+      // dropped, leaking a clone of it gives the copy a reference of its own.
+      let value = ManuallyDrop::new(unsafe { take(self.0) });
+      mem::forget(Value::clone(&value));
+    } else if self.is_boxed() {
+      // SAFETY: the value is alive because this box holds a strong reference.
+      unsafe { Rc::increment_strong_count((self.0 & POINTER) as *const Value) };
+    }
+
+    NanBox(self.0)
+  }
+}
+
+impl Drop for NanBox {
+  fn drop(&mut self) {
+    if self.is_object() || self.is_boxed() {
+      // SAFETY: the box is being dropped, so its reference can go with the value.
+      unsafe { drop(take(self.0)) };
+    }
+  }
+}
+
+impl fmt::Debug for NanBox {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{:?}", self.to_value())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use crate::value::{Map, NativeFunction};
+
+  use std::cell::RefCell;
+
+  #[test]
+  fn nan_boxes_are_eight_bytes() {
+    assert_eq!(8, mem::size_of::<NanBox>());
+  }
+
+  #[test]
+  fn values_survive_a_round_trip() {
+    let list = Rc::new(RefCell::new(vec![Value::Number(1.0)]));
+    let map = Rc::new(RefCell::new(Map::new()));
+    let string: Rc<str> = Rc::from("hello");
+
+    let test_cases: Vec<Value> = vec![
+      Value::Number(0.0),
+      Value::Number(-0.0),
+      Value::Number(1.5),
+      Value::Number(f64::INFINITY),
+      Value::Number(f64::NEG_INFINITY),
+      Value::Number(f64::MIN_POSITIVE),
+      Value::Nil,
+      Value::Boolean(false),
+      Value::Boolean(true),
+      Value::String(Rc::clone(&string)),
+      Value::Range(-1, 3),
+      Value::List(Rc::clone(&list)),
+      Value::Map(Rc::clone(&map)),
+      Value::NativeFunction(Rc::new(NativeFunction {
+        name: "f",
+        arity: 0,
+        function: |_| Ok(Value::Nil),
+      })),
+    ];
+
+    for value in test_cases {
+      let nan_box = NanBox::from(value.clone());
+
+      assert_eq!(value, nan_box.to_value());
+      assert_eq!(value, nan_box.clone().to_value());
+      assert_eq!(value, Value::from(nan_box));
+    }
+
+    // Every copy was dropped.
+    assert_eq!(1, Rc::strong_count(&list));
+    assert_eq!(1, Rc::strong_count(&map));
+    assert_eq!(1, Rc::strong_count(&string));
+  }
+
+  #[test]
+  fn objects_are_stored_as_their_own_rc() {
+    let list = Rc::new(RefCell::new(vec![Value::Number(1.0)]));
+
+    let nan_box = NanBox::from(Value::List(Rc::clone(&list)));
+    assert_eq!(Rc::as_ptr(&list) as u64, nan_box.0 & POINTER);
+    assert_eq!(2, Rc::strong_count(&list));
+
+    let copy = nan_box.clone();
+    assert_eq!(nan_box.0, copy.0);
+    assert_eq!(3, Rc::strong_count(&list));
+
+    match Value::from(copy) {
+      Value::List(copy) => assert!(Rc::ptr_eq(&list, &copy)),
+      value => panic!("expected a list, got {:?}", value),
+    }
+
+    assert_eq!(2, Rc::strong_count(&list));
+
+    drop(nan_box);
+    assert_eq!(1, Rc::strong_count(&list));
+  }
+
+  #[test]
+  fn numbers_keep_their_sign_and_nans_stay_nans() {
+    let negative_zero = NanBox::from(Value::Number(-0.0)).as_number().unwrap();
+    assert!(negative_zero == 0.0 && negative_zero.is_sign_negative());
+
+    // A NaN whose payload looks like a tag is still a number.
+    let nan = f64::from_bits(NIL);
+    assert!(nan.is_nan());
+
+    let test_cases: Vec<f64> = vec![f64::NAN, -f64::NAN, nan, (-1.0_f64).sqrt()];
+
+    for number in test_cases {
+      let nan_box = NanBox::from(Value::Number(number));
+
+      assert!(nan_box.as_number().unwrap().is_nan());
+      assert!(matches!(Value::from(nan_box), Value::Number(number) if number.is_nan()));
+    }
+
+    assert_eq!(None, NanBox::from(Value::Nil).as_number());
+    assert_eq!(None, NanBox::from(Value::Boolean(true)).as_number());
+  }
+}
@@ -0,0 +1,48 @@
+use crate::value::{NativeFunction, Value};
+use crate::vm::RuntimeErrorKind;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// Functions available as globals in every script.
+pub fn functions() -> Vec<NativeFunction> {
+  vec![
+    NativeFunction {
+      name: "len",
+      arity: 1,
+      function: len,
+    },
+    NativeFunction {
+      name: "keys",
+      arity: 1,
+      function: keys,
+    },
+  ]
+}
+
+/// Returns the number of elements in a list or map or characters in a string.
+fn len(arguments: &[Value]) -> Result<Value, RuntimeErrorKind> {
+  match &arguments[0] {
+    Value::List(list) => Ok(Value::Number(list.borrow().len() as f64)),
+    Value::Map(map) => Ok(Value::Number(map.borrow().len() as f64)),
+    Value::String(string) => Ok(Value::Number(string.chars().count() as f64)),
+    value => Err(RuntimeErrorKind::InvalidArgument {
+      function: String::from("len"),
+      argument: value.clone(),
+    }),
+  }
+}
+
+/// Returns a list with the keys of a map in the order they were inserted.
+fn keys(arguments: &[Value]) -> Result<Value, RuntimeErrorKind> {
+  match &arguments[0] {
+    Value::Map(map) => {
+      let keys = map.borrow().keys().map(|key| key.to_value()).collect();
+      Ok(Value::List(Rc::new(RefCell::new(keys))))
+    }
+    value => Err(RuntimeErrorKind::InvalidArgument {
+      function: String::from("keys"),
+      argument: value.clone(),
+    }),
+  }
+}
@@ -0,0 +1,196 @@
+/// The vm's value stack.
+///
+/// Values are stored as they are, or packed into 8 byte nan boxes
+/// with the `nan-boxing` feature. Either way they go in and come
+/// out of the stack as `Value`s.
+#[cfg(feature = "nan-boxing")]
+use crate::nan_box::NanBox;
+use crate::value::Value;
+
+#[cfg(feature = "nan-boxing")]
+type Slot = NanBox;
+
+#[cfg(not(feature = "nan-boxing"))]
+type Slot = Value;
+
+#[cfg(feature = "nan-boxing")]
+fn to_value(slot: &Slot) -> Value {
+  slot.to_value()
+}
+
+#[cfg(feature = "nan-boxing")]
+fn into_value(slot: Slot) -> Value {
+  Value::from(slot)
+}
+
+#[cfg(feature = "nan-boxing")]
+fn as_number(slot: &Slot) -> Option<f64> {
+  slot.as_number()
+}
+
+#[cfg(not(feature = "nan-boxing"))]
+fn to_value(slot: &Slot) -> Value {
+  slot.clone()
+}
+
+#[cfg(not(feature = "nan-boxing"))]
+fn into_value(slot: Slot) -> Value {
+  slot
+}
+
+#[cfg(not(feature = "nan-boxing"))]
+fn as_number(slot: &Slot) -> Option<f64> {
+  match slot {
+    Value::Number(number) => Some(*number),
+    _ => None,
+  }
+}
+
+#[derive(Debug)]
+pub struct Stack {
+  slots: Vec<Slot>,
+}
+
+impl Stack {
+  pub fn with_capacity(capacity: usize) -> Self {
+    Stack {
+      slots: Vec::with_capacity(capacity),
+    }
+  }
+
+  pub fn len(&self) -> usize {
+    self.slots.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.slots.is_empty()
+  }
+
+  pub fn capacity(&self) -> usize {
+    self.slots.capacity()
+  }
+
+  pub fn push(&mut self, value: Value) {
+    self.slots.push(Slot::from(value));
+  }
+
+  pub fn pop(&mut self) -> Option<Value> {
+    self.slots.pop().map(into_value)
+  }
+
+  /// Pops the top two values if they are both numbers, without
+  /// making `Value`s out of them. The right operand is on top.
+  pub fn pop_numbers(&mut self) -> Option<(f64, f64)> {
+    let len = self.slots.len();
+
+    if len < 2 {
+      return None;
+    }
+
+    let a = as_number(&self.slots[len - 2])?;
+    let b = as_number(&self.slots[len - 1])?;
+
+    self.slots.truncate(len - 2);
+
+    Some((a, b))
+  }
+
+  /// Returns a copy of the value at `index`.
+  ///
+  /// Panics if `index` is out of bounds, like indexing a `Vec`.
+  pub fn get(&self, index: usize) -> Value {
+    to_value(&self.slots[index])
+  }
+
+  /// Pushes a copy of the value at `index`.
+  ///
+  /// Panics if `index` is out of bounds, like indexing a `Vec`.
+  pub fn push_copy(&mut self, index: usize) {
+    self.slots.push(self.slots[index].clone());
+  }
+
+  /// Overwrites the value at `index` with a copy of the top of the stack.
+  ///
+  /// Panics if the stack is empty or `index` is out of bounds.
+  pub fn copy_top(&mut self, index: usize) {
+    self.slots[index] = self.slots[self.slots.len() - 1].clone();
+  }
+
+  /// Panics if `index` is out of bounds, like indexing a `Vec`.
+  pub fn set(&mut self, index: usize, value: Value) {
+    self.slots[index] = Slot::from(value);
+  }
+
+  /// Removes every value from `start` to the top of the stack.
+  pub fn drain(&mut self, start: usize) -> Vec<Value> {
+    self.slots.drain(start..).map(into_value).collect()
+  }
+
+  pub fn truncate(&mut self, len: usize) {
+    self.slots.truncate(len);
+  }
+
+  pub fn clear(&mut self) {
+    self.slots.clear();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::rc::Rc;
+
+  #[test]
+  fn values_come_out_the_way_they_went_in() {
+    let mut stack = Stack::with_capacity(4);
+
+    let values = [
+      Value::Number(1.0),
+      Value::Nil,
+      Value::Boolean(true),
+      Value::String(Rc::from("a")),
+      Value::Range(0, 2),
+    ];
+
+    for value in values.iter() {
+      stack.push(value.clone());
+    }
+
+    assert_eq!(values.len(), stack.len());
+
+    for (index, value) in values.iter().enumerate() {
+      assert_eq!(*value, stack.get(index));
+    }
+
+    stack.set(1, Value::Number(2.0));
+    assert_eq!(Value::Number(2.0), stack.get(1));
+
+    stack.push_copy(3);
+    assert_eq!(Value::String(Rc::from("a")), stack.get(5));
+    stack.copy_top(0);
+    assert_eq!(Value::String(Rc::from("a")), stack.get(0));
+    stack.set(0, Value::Number(1.0));
+    stack.pop();
+
+    assert_eq!(Some(Value::Range(0, 2)), stack.pop());
+    assert_eq!(
+      vec![Value::Boolean(true), Value::String(Rc::from("a"))],
+      stack.drain(2)
+    );
+
+    stack.truncate(1);
+    stack.push(Value::Nil);
+    assert_eq!(None, stack.pop_numbers());
+    assert_eq!(2, stack.len());
+
+    stack.set(1, Value::Number(3.0));
+    assert_eq!(Some((1.0, 3.0)), stack.pop_numbers());
+    assert_eq!(None, stack.pop_numbers());
+
+    stack.push(Value::Number(1.0));
+    assert_eq!(Some(Value::Number(1.0)), stack.pop());
+    assert_eq!(None, stack.pop());
+    assert!(stack.is_empty());
+  }
+}
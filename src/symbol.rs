@@ -0,0 +1,124 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// An interned identifier name.
+///
+/// Symbols are cheap to copy, hash and compare, the name they
+/// stand for lives in the `SymbolTable` that created them.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct Symbol(usize);
+
+/// Names of the identifiers seen so far and the slots of the global variables.
+///
+/// The compiler and the vm share the same table, so a symbol or slot
+/// compiled into a chunk means the same name when it's executed.
+/// It outlives each compilation so REPL inputs see the same globals.
+#[derive(Debug, Default)]
+pub struct SymbolTable {
+  names: Vec<Rc<str>>,
+  symbols: HashMap<Rc<str>, Symbol>,
+  /// Name of the global variable in each slot.
+  globals: Vec<Symbol>,
+  global_slots: HashMap<Symbol, usize>,
+}
+
+impl SymbolTable {
+  pub fn new() -> Self {
+    SymbolTable::default()
+  }
+
+  /// Returns the symbol for `name`, creating it the first time `name` is seen.
+  pub fn intern(&mut self, name: &str) -> Symbol {
+    if let Some(symbol) = self.symbols.get(name) {
+      return *symbol;
+    }
+
+    let symbol = Symbol(self.names.len());
+    let name: Rc<str> = Rc::from(name);
+
+    self.names.push(Rc::clone(&name));
+    self.symbols.insert(name, symbol);
+
+    symbol
+  }
+
+  pub fn name(&self, symbol: Symbol) -> Rc<str> {
+    Rc::clone(&self.names[symbol.0])
+  }
+
+  /// Returns the slot of the global variable called `name`,
+  /// the first time a name is seen it gets the next free slot.
+  pub fn global_slot(&mut self, name: &str) -> usize {
+    let symbol = self.intern(name);
+
+    if let Some(slot) = self.global_slots.get(&symbol) {
+      return *slot;
+    }
+
+    let slot = self.globals.len();
+
+    self.globals.push(symbol);
+    self.global_slots.insert(symbol, slot);
+
+    slot
+  }
+
+  pub fn global_name(&self, slot: usize) -> Rc<str> {
+    self.name(self.globals[slot])
+  }
+
+  /// How many global slots have been handed out.
+  pub fn global_count(&self) -> usize {
+    self.globals.len()
+  }
+
+  pub fn len(&self) -> usize {
+    self.names.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.names.is_empty()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn the_same_name_is_interned_once() {
+    let mut symbols = SymbolTable::new();
+
+    let test_cases: Vec<(&str, usize)> = vec![("x", 1), ("y", 2), ("x", 2), ("y", 2), ("z", 3)];
+
+    for (name, expected_len) in test_cases {
+      let symbol = symbols.intern(name);
+
+      assert_eq!(symbol, symbols.intern(name));
+      assert_eq!(name, &*symbols.name(symbol));
+      assert_eq!(expected_len, symbols.len());
+    }
+
+    assert_ne!(symbols.intern("x"), symbols.intern("y"));
+  }
+
+  #[test]
+  fn globals_get_the_next_free_slot() {
+    let mut symbols = SymbolTable::new();
+
+    // Property names are interned too but don't take a global slot.
+    symbols.intern("field");
+
+    let test_cases: Vec<(&str, usize)> = vec![("a", 0), ("b", 1), ("a", 0), ("field", 2)];
+
+    for (name, expected_slot) in test_cases {
+      let slot = symbols.global_slot(name);
+
+      assert_eq!(expected_slot, slot);
+      assert_eq!(name, &*symbols.global_name(slot));
+    }
+
+    assert_eq!(3, symbols.global_count());
+    assert_eq!(3, symbols.len());
+  }
+}
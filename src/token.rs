@@ -1,5 +1,11 @@
+/// Where a token is in the source code.
+///
+/// `start` and `end` are byte offsets, `end` is exclusive.
+/// `line` and `column` are the position of the first character of the token.
 #[derive(Debug, PartialEq, Clone)]
-pub struct SourceLocation {
+pub struct Span {
+  pub start: usize,
+  pub end: usize,
   pub line: usize,
   pub column: usize,
 }
@@ -11,7 +17,9 @@ pub enum Token {
   LeftBrace,
   RightBrace,
   Comma,
+  Colon,
   Dot,
+  DotDot,
   Minus,
   Plus,
   Semicolon,
@@ -33,12 +41,15 @@ pub enum Token {
   String(String),
   Number(String),
   And,
+  Break,
   Class,
+  Continue,
   Else,
   False,
   For,
   Function,
   If,
+  In,
   Nil,
   Or,
   Print,
@@ -61,8 +72,18 @@ pub fn lookup_identifier(lexeme: String) -> Token {
     "false" => Token::False,
     "if" => Token::If,
     "else" => Token::Else,
+    "while" => Token::While,
     "nil" => Token::Nil,
     "print" => Token::Print,
+    "and" => Token::And,
+    "or" => Token::Or,
+    "for" => Token::For,
+    "in" => Token::In,
+    "break" => Token::Break,
+    "continue" => Token::Continue,
+    "class" => Token::Class,
+    "this" => Token::This,
+    "super" => Token::Super,
     _ => Token::Identifier(lexeme),
   }
 }
@@ -1,7 +1,318 @@
-#[derive(Debug, PartialEq, Clone)]
+use crate::chunk::Chunk;
+use crate::vm::RuntimeErrorKind;
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+/// Tells a closure where to find a variable it captures
+/// when the closure is created.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct UpvalueDescriptor {
+  /// Whether the variable is a local of the enclosing function
+  /// or one of the upvalues captured by the enclosing function.
+  pub is_local: bool,
+  /// Slot of the local or index of the upvalue in the enclosing function.
+  pub index: usize,
+}
+
+#[derive(Debug)]
+pub struct Function {
+  /// None for the top-level script.
+  pub name: Option<String>,
+  pub arity: usize,
+  pub chunk: Chunk,
+  pub upvalues: Vec<UpvalueDescriptor>,
+}
+
+impl fmt::Display for Function {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match &self.name {
+      None => write!(f, "<script>"),
+      Some(name) => write!(f, "<fn {}>", name),
+    }
+  }
+}
+
+/// A function implemented in Rust that can be called from scripts.
+#[derive(Debug)]
+pub struct NativeFunction {
+  pub name: &'static str,
+  pub arity: usize,
+  pub function: fn(&[Value]) -> Result<Value, RuntimeErrorKind>,
+}
+
+/// A value that can be used as a map key.
+///
+/// Numbers are compared by their bits, -0.0 is stored as 0.0
+/// so it finds the same entry. NaN is never equal to itself
+/// so it can't be used as a key.
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub enum MapKey {
+  Boolean(bool),
+  Number(u64),
+  String(Rc<str>),
+  Nil,
+}
+
+impl MapKey {
+  /// Returns None when `value` can't be used as a map key.
+  pub fn from_value(value: &Value) -> Option<MapKey> {
+    match value {
+      Value::Boolean(boolean) => Some(MapKey::Boolean(*boolean)),
+      Value::Number(number) if number.is_nan() => None,
+      Value::Number(number) if *number == 0.0 => Some(MapKey::Number(0.0_f64.to_bits())),
+      Value::Number(number) => Some(MapKey::Number(number.to_bits())),
+      Value::String(string) => Some(MapKey::String(Rc::clone(string))),
+      Value::Nil => Some(MapKey::Nil),
+      _ => None,
+    }
+  }
+
+  pub fn to_value(&self) -> Value {
+    match self {
+      MapKey::Boolean(boolean) => Value::Boolean(*boolean),
+      MapKey::Number(bits) => Value::Number(f64::from_bits(*bits)),
+      MapKey::String(string) => Value::String(Rc::clone(string)),
+      MapKey::Nil => Value::Nil,
+    }
+  }
+}
+
+/// A map that remembers the order its keys were inserted in.
+#[derive(Debug, Default)]
+pub struct Map {
+  entries: Vec<(MapKey, Value)>,
+  /// Position of each key in `entries`.
+  positions: HashMap<MapKey, usize>,
+}
+
+impl Map {
+  pub fn new() -> Self {
+    Map::default()
+  }
+
+  pub fn get(&self, key: &MapKey) -> Option<&Value> {
+    self
+      .positions
+      .get(key)
+      .map(|position| &self.entries[*position].1)
+  }
+
+  pub fn insert(&mut self, key: MapKey, value: Value) {
+    match self.positions.get(&key) {
+      Some(position) => self.entries[*position].1 = value,
+      None => {
+        self.positions.insert(key.clone(), self.entries.len());
+        self.entries.push((key, value));
+      }
+    }
+  }
+
+  pub fn len(&self) -> usize {
+    self.entries.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.entries.is_empty()
+  }
+
+  /// Returns the keys in insertion order.
+  pub fn keys(&self) -> impl Iterator<Item = &MapKey> {
+    self.entries.iter().map(|(key, _value)| key)
+  }
+
+  pub fn iter(&self) -> impl Iterator<Item = &(MapKey, Value)> {
+    self.entries.iter()
+  }
+}
+
+/// Progress of a `for in` loop over a value.
+#[derive(Debug)]
+pub enum IteratorState {
+  List {
+    list: Rc<RefCell<Vec<Value>>>,
+    index: usize,
+  },
+  /// Keys are copied when the loop starts, in insertion order.
+  Keys {
+    keys: Vec<Value>,
+    index: usize,
+  },
+  Chars {
+    string: Rc<str>,
+    offset: usize,
+  },
+  Range {
+    next: i64,
+    end: i64,
+  },
+  /// An instance whose `hasNext` method returns whether
+  /// there are more values and `next` method returns them.
+  Instance(Value),
+}
+
+/// A variable captured by a closure.
+#[derive(Debug)]
+pub enum Upvalue {
+  /// The variable is still on the stack at this index.
+  Open(usize),
+  /// The variable went out of scope and was moved into the upvalue.
+  Closed(Value),
+}
+
+#[derive(Debug)]
+pub struct Closure {
+  pub function: Rc<Function>,
+  /// Shared with every other closure that captures the same variable.
+  pub upvalues: Vec<Rc<RefCell<Upvalue>>>,
+}
+
+#[derive(Debug)]
+pub struct Class {
+  pub name: String,
+  pub methods: HashMap<String, Rc<Closure>>,
+}
+
+#[derive(Debug)]
+pub struct Instance {
+  pub class: Rc<RefCell<Class>>,
+  pub fields: HashMap<String, Value>,
+}
+
+/// A method that remembers the instance it was accessed from.
+#[derive(Debug)]
+pub struct BoundMethod {
+  pub receiver: Value,
+  pub method: Rc<Closure>,
+}
+
+#[derive(Debug, Clone)]
 pub enum Value {
   Boolean(bool),
   Number(f64),
-  Identifier(String),
+  String(Rc<str>),
+  Function(Rc<Function>),
+  Closure(Rc<Closure>),
+  NativeFunction(Rc<NativeFunction>),
+  List(Rc<RefCell<Vec<Value>>>),
+  Map(Rc<RefCell<Map>>),
+  /// Integers from the start up to, but not including, the end.
+  Range(i64, i64),
+  Iterator(Rc<RefCell<IteratorState>>),
+  Class(Rc<RefCell<Class>>),
+  Instance(Rc<RefCell<Instance>>),
+  BoundMethod(Rc<BoundMethod>),
   Nil,
 }
+
+impl Value {
+  /// `nil` and `false` are falsey, every other value is truthy.
+  pub fn is_falsey(&self) -> bool {
+    matches!(self, Value::Nil | Value::Boolean(false))
+  }
+}
+
+impl PartialEq for Value {
+  fn eq(&self, other: &Value) -> bool {
+    match (self, other) {
+      (Value::Boolean(a), Value::Boolean(b)) => a == b,
+      (Value::Number(a), Value::Number(b)) => a == b,
+      (Value::String(a), Value::String(b)) => a == b,
+      // Objects are only equal to themselves.
+      (Value::Function(a), Value::Function(b)) => Rc::ptr_eq(a, b),
+      (Value::Closure(a), Value::Closure(b)) => Rc::ptr_eq(a, b),
+      (Value::NativeFunction(a), Value::NativeFunction(b)) => Rc::ptr_eq(a, b),
+      (Value::List(a), Value::List(b)) => Rc::ptr_eq(a, b),
+      (Value::Map(a), Value::Map(b)) => Rc::ptr_eq(a, b),
+      (Value::Range(a_start, a_end), Value::Range(b_start, b_end)) => {
+        a_start == b_start && a_end == b_end
+      }
+      (Value::Iterator(a), Value::Iterator(b)) => Rc::ptr_eq(a, b),
+      (Value::Class(a), Value::Class(b)) => Rc::ptr_eq(a, b),
+      (Value::Instance(a), Value::Instance(b)) => Rc::ptr_eq(a, b),
+      (Value::BoundMethod(a), Value::BoundMethod(b)) => Rc::ptr_eq(a, b),
+      (Value::Nil, Value::Nil) => true,
+      _ => false,
+    }
+  }
+}
+
+impl fmt::Display for Value {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    self.write(f, &mut Vec::new())
+  }
+}
+
+impl Value {
+  /// `containers` are the lists and maps being written, one that
+  /// contains itself is written as `[...]` or `{...}` the second time.
+  fn write(&self, f: &mut fmt::Formatter, containers: &mut Vec<*const ()>) -> fmt::Result {
+    match self {
+      Value::Boolean(boolean) => write!(f, "{}", boolean),
+      Value::Number(number) => write!(f, "{}", number),
+      Value::String(string) => write!(f, "{}", string),
+      Value::Function(function) => write!(f, "{}", function),
+      Value::Closure(closure) => write!(f, "{}", closure.function),
+      Value::NativeFunction(native_function) => {
+        write!(f, "<native fn {}>", native_function.name)
+      }
+      Value::List(list) => {
+        let pointer = Rc::as_ptr(list) as *const ();
+
+        if containers.contains(&pointer) {
+          return write!(f, "[...]");
+        }
+
+        containers.push(pointer);
+
+        write!(f, "[")?;
+
+        for (i, value) in list.borrow().iter().enumerate() {
+          if i > 0 {
+            write!(f, ", ")?;
+          }
+
+          value.write(f, containers)?;
+        }
+
+        containers.pop();
+
+        write!(f, "]")
+      }
+      Value::Map(map) => {
+        let pointer = Rc::as_ptr(map) as *const ();
+
+        if containers.contains(&pointer) {
+          return write!(f, "{{...}}");
+        }
+
+        containers.push(pointer);
+
+        write!(f, "{{")?;
+
+        for (i, (key, value)) in map.borrow().iter().enumerate() {
+          if i > 0 {
+            write!(f, ", ")?;
+          }
+
+          write!(f, "{}: ", key.to_value())?;
+          value.write(f, containers)?;
+        }
+
+        containers.pop();
+
+        write!(f, "}}")
+      }
+      Value::Range(start, end) => write!(f, "{}..{}", start, end),
+      Value::Iterator(_) => write!(f, "<iterator>"),
+      Value::Class(class) => write!(f, "{}", class.borrow().name),
+      Value::Instance(instance) => {
+        write!(f, "{} instance", instance.borrow().class.borrow().name)
+      }
+      Value::BoundMethod(bound_method) => write!(f, "{}", bound_method.method.function),
+      Value::Nil => write!(f, "nil"),
+    }
+  }
+}
@@ -1,125 +1,2631 @@
-use crate::chunk::{Chunk, OpCode};
-use crate::value::Value;
+use crate::chunk::OpCode;
+use crate::compiler::CompileError;
+use crate::native;
+use crate::stack::Stack;
+use crate::symbol::SymbolTable;
+use crate::value::{
+  BoundMethod, Class, Closure, Function, Instance, IteratorState, Map, MapKey, Upvalue, Value,
+};
 
-use std::collections::{HashMap, VecDeque};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+/// How many function calls can be active at the same time.
+const MAX_FRAMES: usize = 256;
+
+/// How many values fit on the stack unless the vm is created with another limit.
+pub const STACK_MAX: usize = MAX_FRAMES * 256;
+
+/// How many values the stack has room for before it has to grow,
+/// enough for most scripts without allocating all of `STACK_MAX`.
+const STACK_INITIAL_CAPACITY: usize = 256;
 
 #[derive(Debug)]
-pub struct Vm {
+struct CallFrame {
+  closure: Rc<Closure>,
   ip: usize,
-  stack: VecDeque<Value>,
-  globals: HashMap<String, Value>,
+  /// Index of the first stack slot the function can use.
+  /// It holds the function being called and is followed by its arguments.
+  slots_starts_at: usize,
+}
+
+pub struct Vm {
+  frames: Vec<CallFrame>,
+  /// Allocated up front for the common case, so most pushes never have to grow it.
+  stack: Stack,
+  /// Pushing past this many values is a stack overflow.
+  stack_max: usize,
+  /// Value of the global variable in each slot, None until it's defined.
+  globals: Vec<Option<Value>>,
+  /// Identifier names, shared with the compilers of the code this vm runs.
+  symbols: Rc<RefCell<SymbolTable>>,
+  /// Upvalues pointing to variables that are still on the stack.
+  open_upvalues: Vec<Rc<RefCell<Upvalue>>>,
+  output: Box<dyn Write>,
 }
 
 #[derive(Debug)]
 pub enum InterpretResult {
   Ok(Option<Value>),
-  CompileError(String),
-  RuntimeError(String),
+  CompileError(Vec<CompileError>),
+  RuntimeError(RuntimeError),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum RuntimeErrorKind {
+  OperandMustBeNumber(Value),
+  OperandsMustBeNumbers(Value, Value),
+  OperandsMustBeNumbersOrStrings(Value, Value),
+  StackUnderflow,
+  StackOverflow,
+  UndefinedVariable(String),
+  ExpectedFunction(Value),
+  NotCallable(Value),
+  ArityMismatch {
+    expected: usize,
+    got: usize,
+  },
+  ExpectedClass(Value),
+  NotAnInstance(Value),
+  UndefinedProperty(String),
+  SuperclassMustBeClass(Value),
+  InvalidArgument {
+    function: String,
+    argument: Value,
+  },
+  NotIndexable(Value),
+  IndexMustBeInteger(Value),
+  IndexOutOfBounds {
+    index: i64,
+    length: usize,
+  },
+  UnhashableKey(Value),
+  KeyNotFound(Value),
+  RangeBoundsMustBeIntegers(Value, Value),
+  NotIterable(Value),
+  /// Writing the output of `print` failed.
+  Io(io::ErrorKind),
+}
+
+impl fmt::Display for RuntimeErrorKind {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      RuntimeErrorKind::OperandMustBeNumber(value) => {
+        write!(f, "operand must be a number, got {:?}", value)
+      }
+      RuntimeErrorKind::OperandsMustBeNumbers(a, b) => {
+        write!(f, "operands must be numbers, got {:?} and {:?}", a, b)
+      }
+      RuntimeErrorKind::OperandsMustBeNumbersOrStrings(a, b) => write!(
+        f,
+        "operands must be two numbers or two strings, got {:?} and {:?}",
+        a, b
+      ),
+      RuntimeErrorKind::StackUnderflow => write!(f, "stack underflow"),
+      RuntimeErrorKind::StackOverflow => write!(f, "stack overflow"),
+      RuntimeErrorKind::UndefinedVariable(name) => write!(f, "undefined variable {}", name),
+      RuntimeErrorKind::ExpectedFunction(value) => {
+        write!(f, "expected function, got {:?}", value)
+      }
+      RuntimeErrorKind::NotCallable(value) => {
+        write!(f, "can only call functions and classes, got {}", value)
+      }
+      RuntimeErrorKind::ArityMismatch { expected, got } => {
+        write!(f, "expected {} arguments but got {}", expected, got)
+      }
+      RuntimeErrorKind::ExpectedClass(value) => write!(f, "expected class, got {:?}", value),
+      RuntimeErrorKind::NotAnInstance(value) => {
+        write!(f, "only instances have properties, got {}", value)
+      }
+      RuntimeErrorKind::UndefinedProperty(name) => write!(f, "undefined property {}", name),
+      RuntimeErrorKind::SuperclassMustBeClass(value) => {
+        write!(f, "superclass must be a class, got {}", value)
+      }
+      RuntimeErrorKind::InvalidArgument { function, argument } => {
+        write!(f, "invalid argument for {}, got {:?}", function, argument)
+      }
+      RuntimeErrorKind::NotIndexable(value) => {
+        write!(f, "can only index lists and maps, got {}", value)
+      }
+      RuntimeErrorKind::IndexMustBeInteger(value) => {
+        write!(f, "index must be an integer, got {:?}", value)
+      }
+      RuntimeErrorKind::IndexOutOfBounds { index, length } => write!(
+        f,
+        "index {} is out of bounds for list of length {}",
+        index, length
+      ),
+      RuntimeErrorKind::UnhashableKey(value) => {
+        write!(f, "value can't be used as a map key, got {:?}", value)
+      }
+      RuntimeErrorKind::KeyNotFound(value) => write!(f, "key {:?} not found in map", value),
+      RuntimeErrorKind::RangeBoundsMustBeIntegers(start, end) => write!(
+        f,
+        "range bounds must be integers, got {:?} and {:?}",
+        start, end
+      ),
+      RuntimeErrorKind::NotIterable(value) => write!(f, "value is not iterable, got {}", value),
+      RuntimeErrorKind::Io(kind) => write!(f, "unable to write output: {}", kind),
+    }
+  }
+}
+
+/// A function that was being executed when a runtime error happened.
+#[derive(Debug, PartialEq, Clone)]
+pub struct StackTraceEntry {
+  pub function: String,
+  pub line: usize,
+}
+
+/// An error raised while executing a chunk.
+///
+/// `offset` is the index of the instruction that failed and
+/// `line` and `column` are where the instruction was compiled from.
+/// `stack_trace` starts at the function that failed and ends at the script.
+/// Errors raised before the script starts running, like a stack too
+/// small to hold it, have no location: they are at line 0 with no stack trace.
+#[derive(Debug, PartialEq, Clone)]
+pub struct RuntimeError {
+  pub kind: RuntimeErrorKind,
+  pub offset: usize,
+  pub line: usize,
+  pub column: usize,
+  pub stack_trace: Vec<StackTraceEntry>,
+}
+
+impl fmt::Display for RuntimeError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    if self.line == 0 {
+      write!(f, "{}", self.kind)?;
+    } else {
+      write!(
+        f,
+        "{} at line {}, column {}",
+        self.kind, self.line, self.column
+      )?;
+    }
+
+    for entry in self.stack_trace.iter() {
+      write!(f, "\n  in {} at line {}", entry.function, entry.line)?;
+    }
+
+    Ok(())
+  }
+}
+
+impl Default for Vm {
+  fn default() -> Self {
+    Self::new()
+  }
 }
 
 impl Vm {
   pub fn new() -> Self {
+    Vm::with_output(Box::new(io::stdout()))
+  }
+
+  /// Creates a vm that writes the output of `print` statements to `output`.
+  pub fn with_output(output: Box<dyn Write>) -> Self {
+    Vm::with_stack_max(output, STACK_MAX)
+  }
+
+  /// Creates a vm that fails with a stack overflow instead of
+  /// holding more than `stack_max` values on the stack.
+  /// The stack starts small and grows as needed up to the limit.
+  pub fn with_stack_max(output: Box<dyn Write>, stack_max: usize) -> Self {
+    let mut symbols = SymbolTable::new();
+    let mut globals = Vec::new();
+
+    for function in native::functions() {
+      let slot = symbols.global_slot(function.name);
+
+      globals.resize(slot + 1, None);
+      globals[slot] = Some(Value::NativeFunction(Rc::new(function)));
+    }
+
     Vm {
+      frames: Vec::new(),
+      stack: Stack::with_capacity(stack_max.min(STACK_INITIAL_CAPACITY)),
+      stack_max,
+      globals,
+      symbols: Rc::new(RefCell::new(symbols)),
+      open_upvalues: Vec::new(),
+      output,
+    }
+  }
+
+  /// The symbol table scripts run by this vm have to be compiled with.
+  pub fn symbols(&self) -> Rc<RefCell<SymbolTable>> {
+    Rc::clone(&self.symbols)
+  }
+
+  pub fn run(&mut self, script: Function) -> InterpretResult {
+    let script = Rc::new(Closure {
+      function: Rc::new(script),
+      upvalues: Vec::new(),
+    });
+
+    let result = self
+      .push(Value::Closure(Rc::clone(&script)))
+      .and_then(|_| self.call(script, 0))
+      .and_then(|_| self.execute(0));
+
+    match result {
+      Ok(value) => InterpretResult::Ok(Some(value)),
+      Err(kind) => {
+        let error = self.runtime_error(kind);
+
+        self.stack.clear();
+        self.frames.clear();
+        self.open_upvalues.clear();
+
+        InterpretResult::RuntimeError(error)
+      }
+    }
+  }
+
+  fn runtime_error(&self, kind: RuntimeErrorKind) -> RuntimeError {
+    // ip has already been moved past the instruction each frame is executing.
+    let stack_trace = self
+      .frames
+      .iter()
+      .rev()
+      .map(|frame| StackTraceEntry {
+        function: frame
+          .closure
+          .function
+          .name
+          .clone()
+          .unwrap_or_else(|| String::from("script")),
+        line: frame.closure.function.chunk.location_of(frame.ip - 1).line,
+      })
+      .collect();
+
+    match self.frames.last() {
+      Some(frame) if frame.ip > 0 => {
+        let location = frame.closure.function.chunk.location_of(frame.ip - 1);
+
+        RuntimeError {
+          kind,
+          offset: frame.ip - 1,
+          line: location.line,
+          column: location.column,
+          stack_trace,
+        }
+      }
+      _ => RuntimeError {
+        kind,
+        offset: 0,
+        line: 0,
+        column: 0,
+        stack_trace: Vec::new(),
+      },
+    }
+  }
+
+  fn frame(&self) -> &CallFrame {
+    self.frames.last().expect("there are no call frames")
+  }
+
+  fn frame_mut(&mut self) -> &mut CallFrame {
+    self.frames.last_mut().expect("there are no call frames")
+  }
+
+  fn push(&mut self, value: Value) -> Result<(), RuntimeErrorKind> {
+    self.reserve_slot()?;
+    self.stack.push(value);
+
+    Ok(())
+  }
+
+  /// Checks there's room on the stack for one more value.
+  fn reserve_slot(&self) -> Result<(), RuntimeErrorKind> {
+    if self.stack.len() == self.stack_max {
+      return Err(RuntimeErrorKind::StackOverflow);
+    }
+
+    Ok(())
+  }
+
+  fn pop(&mut self) -> Result<Value, RuntimeErrorKind> {
+    self.stack.pop().ok_or(RuntimeErrorKind::StackUnderflow)
+  }
+
+  /// Pops the two operands of a binary instruction,
+  /// the right operand is on top of the stack.
+  fn pop_operands(&mut self) -> Result<(Value, Value), RuntimeErrorKind> {
+    if self.stack.len() < 2 {
+      return Err(RuntimeErrorKind::StackUnderflow);
+    }
+
+    // SAFETY: the stack was just checked to hold at least two values.
+    let (b, a) = unsafe {
+      (
+        self.stack.pop().unwrap_unchecked(),
+        self.stack.pop().unwrap_unchecked(),
+      )
+    };
+
+    Ok((a, b))
+  }
+
+  fn peek(&self) -> Result<Value, RuntimeErrorKind> {
+    self.peek_at(0)
+  }
+
+  /// Returns the value `distance` slots below the top of the stack.
+  fn peek_at(&self, distance: usize) -> Result<Value, RuntimeErrorKind> {
+    self
+      .stack
+      .len()
+      .checked_sub(distance + 1)
+      .map(|index| self.stack.get(index))
+      .ok_or(RuntimeErrorKind::StackUnderflow)
+  }
+
+  fn call_value(&mut self, callee: Value, argument_count: usize) -> Result<(), RuntimeErrorKind> {
+    match callee {
+      Value::Closure(closure) => self.call(closure, argument_count),
+      Value::NativeFunction(native_function) => {
+        if argument_count != native_function.arity {
+          return Err(RuntimeErrorKind::ArityMismatch {
+            expected: native_function.arity,
+            got: argument_count,
+          });
+        }
+
+        let arguments_start_at = self.stack.len() - argument_count;
+
+        let arguments = self.stack.drain(arguments_start_at);
+
+        let result = (native_function.function)(&arguments)?;
+
+        // Replaces the function being called with its result.
+        self.pop()?;
+        self.push(result)?;
+
+        Ok(())
+      }
+      Value::BoundMethod(bound_method) => {
+        // The method finds the instance it was called on in slot 0.
+        let slot = self.stack.len() - argument_count - 1;
+        self.stack.set(slot, bound_method.receiver.clone());
+        self.call(Rc::clone(&bound_method.method), argument_count)
+      }
+      Value::Class(class) => {
+        let instance = Value::Instance(Rc::new(RefCell::new(Instance {
+          class: Rc::clone(&class),
+          fields: HashMap::new(),
+        })));
+
+        let slot = self.stack.len() - argument_count - 1;
+        self.stack.set(slot, instance);
+
+        let initializer = class.borrow().methods.get("init").cloned();
+
+        match initializer {
+          Some(initializer) => self.call(initializer, argument_count),
+          None if argument_count != 0 => Err(RuntimeErrorKind::ArityMismatch {
+            expected: 0,
+            got: argument_count,
+          }),
+          None => Ok(()),
+        }
+      }
+      value => Err(RuntimeErrorKind::NotCallable(value)),
+    }
+  }
+
+  /// Pushes `class`'s method called `name` bound to the instance on top of the stack.
+  fn bind_method(
+    &mut self,
+    class: &Rc<RefCell<Class>>,
+    name: &str,
+  ) -> Result<(), RuntimeErrorKind> {
+    let method = match class.borrow().methods.get(name) {
+      None => return Err(RuntimeErrorKind::UndefinedProperty(name.to_owned())),
+      Some(method) => Rc::clone(method),
+    };
+
+    let receiver = self.pop()?;
+
+    self.push(Value::BoundMethod(Rc::new(BoundMethod {
+      receiver,
+      method,
+    })))?;
+
+    Ok(())
+  }
+
+  /// Converts `index` to a position in a list of `length` elements,
+  /// negative indexes count from the end of the list.
+  fn list_index(index: &Value, length: usize) -> Result<usize, RuntimeErrorKind> {
+    let index = match index {
+      Value::Number(number) if number.fract() == 0.0 => *number as i64,
+      value => return Err(RuntimeErrorKind::IndexMustBeInteger(value.clone())),
+    };
+
+    let position = if index < 0 {
+      index + length as i64
+    } else {
+      index
+    };
+
+    if position < 0 || position >= length as i64 {
+      return Err(RuntimeErrorKind::IndexOutOfBounds { index, length });
+    }
+
+    Ok(position as usize)
+  }
+
+  fn map_key(value: &Value) -> Result<MapKey, RuntimeErrorKind> {
+    MapKey::from_value(value).ok_or_else(|| RuntimeErrorKind::UnhashableKey(value.clone()))
+  }
+
+  fn read_identifier(&self, index: usize) -> String {
+    let symbol = self.frame().closure.function.chunk.identifiers[index];
+
+    self.symbols.borrow().name(symbol).to_string()
+  }
+
+  fn undefined_variable(&self, slot: usize) -> RuntimeErrorKind {
+    RuntimeErrorKind::UndefinedVariable(self.symbols.borrow().global_name(slot).to_string())
+  }
+
+  fn call(&mut self, closure: Rc<Closure>, argument_count: usize) -> Result<(), RuntimeErrorKind> {
+    if argument_count != closure.function.arity {
+      return Err(RuntimeErrorKind::ArityMismatch {
+        expected: closure.function.arity,
+        got: argument_count,
+      });
+    }
+
+    if self.frames.len() == MAX_FRAMES {
+      return Err(RuntimeErrorKind::StackOverflow);
+    }
+
+    self.frames.push(CallFrame {
+      closure,
       ip: 0,
-      stack: VecDeque::new(),
-      globals: HashMap::new(),
+      slots_starts_at: self.stack.len() - argument_count - 1,
+    });
+
+    Ok(())
+  }
+
+  /// Returns the upvalue pointing to the variable at `stack_index`,
+  /// closures capturing the same variable share the same upvalue.
+  fn capture_upvalue(&mut self, stack_index: usize) -> Rc<RefCell<Upvalue>> {
+    let open_upvalue = self
+      .open_upvalues
+      .iter()
+      .find(|upvalue| matches!(*upvalue.borrow(), Upvalue::Open(index) if index == stack_index));
+
+    if let Some(upvalue) = open_upvalue {
+      return Rc::clone(upvalue);
+    }
+
+    let upvalue = Rc::new(RefCell::new(Upvalue::Open(stack_index)));
+
+    self.open_upvalues.push(Rc::clone(&upvalue));
+
+    upvalue
+  }
+
+  /// Moves every variable at or above `stack_index` that
+  /// was captured by a closure into its upvalue.
+  fn close_upvalues(&mut self, stack_index: usize) {
+    let stack = &self.stack;
+
+    self.open_upvalues.retain(|upvalue| {
+      let mut upvalue = upvalue.borrow_mut();
+
+      match *upvalue {
+        Upvalue::Open(index) if index >= stack_index => {
+          *upvalue = Upvalue::Closed(stack.get(index));
+          false
+        }
+        _ => true,
+      }
+    });
+  }
+
+  fn binary_number_operation(
+    &mut self,
+    operation: fn(f64, f64) -> Value,
+  ) -> Result<(), RuntimeErrorKind> {
+    if let Some((a, b)) = self.stack.pop_numbers() {
+      return self.push(operation(a, b));
+    }
+
+    let (a, b) = self.pop_operands()?;
+
+    Err(RuntimeErrorKind::OperandsMustBeNumbers(a, b))
+  }
+
+  /// Calls the method called `name` on `receiver` and runs it to completion,
+  /// used by instructions that need the result of a method to continue.
+  fn call_method_now(&mut self, receiver: Value, name: &str) -> Result<Value, RuntimeErrorKind> {
+    let method = match &receiver {
+      Value::Instance(instance) => instance.borrow().class.borrow().methods.get(name).cloned(),
+      value => return Err(RuntimeErrorKind::NotAnInstance(value.clone())),
+    };
+
+    let method = method.ok_or_else(|| RuntimeErrorKind::UndefinedProperty(name.to_owned()))?;
+
+    let frame_count = self.frames.len();
+
+    self.push(receiver)?;
+
+    self.call(method, 0)?;
+
+    self.execute(frame_count)
+  }
+
+  fn iterator(&mut self, value: Value) -> Result<IteratorState, RuntimeErrorKind> {
+    match value {
+      Value::List(list) => Ok(IteratorState::List { list, index: 0 }),
+      Value::Map(map) => Ok(IteratorState::Keys {
+        keys: map.borrow().keys().map(|key| key.to_value()).collect(),
+        index: 0,
+      }),
+      Value::String(string) => Ok(IteratorState::Chars { string, offset: 0 }),
+      Value::Range(start, end) => Ok(IteratorState::Range { next: start, end }),
+      Value::Instance(ref instance)
+        if instance
+          .borrow()
+          .class
+          .borrow()
+          .methods
+          .contains_key("iter") =>
+      {
+        let iterator = self.call_method_now(value, "iter")?;
+        Ok(IteratorState::Instance(iterator))
+      }
+      value => Err(RuntimeErrorKind::NotIterable(value)),
+    }
+  }
+
+  /// Returns None when the iterator is exhausted.
+  fn iterator_next(
+    &mut self,
+    iterator: &Rc<RefCell<IteratorState>>,
+  ) -> Result<Option<Value>, RuntimeErrorKind> {
+    let mut state = iterator.borrow_mut();
+
+    match &mut *state {
+      IteratorState::List { list, index } => {
+        let next = list.borrow().get(*index).cloned();
+        *index += 1;
+        Ok(next)
+      }
+      IteratorState::Keys { keys, index } => {
+        let next = keys.get(*index).cloned();
+        *index += 1;
+        Ok(next)
+      }
+      IteratorState::Chars { string, offset } => match string[*offset..].chars().next() {
+        None => Ok(None),
+        Some(character) => {
+          *offset += character.len_utf8();
+          Ok(Some(Value::String(Rc::from(character.to_string()))))
+        }
+      },
+      IteratorState::Range { next, end } => {
+        if next >= end {
+          return Ok(None);
+        }
+
+        *next += 1;
+
+        Ok(Some(Value::Number((*next - 1) as f64)))
+      }
+      IteratorState::Instance(instance) => {
+        let instance = instance.clone();
+
+        // The methods may use the iterator, it can't stay borrowed.
+        drop(state);
+
+        // Asking first instead of ending at a special value
+        // lets `next` return any value, nil included.
+        if self
+          .call_method_now(instance.clone(), "hasNext")?
+          .is_falsey()
+        {
+          return Ok(None);
+        }
+
+        self.call_method_now(instance, "next").map(Some)
+      }
     }
   }
 
-  pub fn run(&mut self, chunk: Chunk) -> InterpretResult {
-    dbg!(&chunk);
-    while self.ip < chunk.code.len() {
-      let instruction = &chunk.code[self.ip];
+  /// Executes instructions until the number of call frames drops to `frame_count`
+  /// and returns the value returned by the last frame that finished.
+  fn execute(&mut self, frame_count: usize) -> Result<Value, RuntimeErrorKind> {
+    loop {
+      let frame = self.frame_mut();
+
+      let instruction = frame.closure.function.chunk.code[frame.ip];
 
-      self.ip += 1;
+      frame.ip += 1;
+
+      let slots_starts_at = frame.slots_starts_at;
 
       match instruction {
         OpCode::Return => {
-          return InterpretResult::Ok(self.stack.pop_back());
+          let result = self.pop()?;
+
+          let frame = self.frames.pop().expect("there are no call frames");
+
+          self.close_upvalues(frame.slots_starts_at);
+
+          self.stack.truncate(frame.slots_starts_at);
+
+          if self.frames.len() == frame_count {
+            return Ok(result);
+          }
+
+          self.push(result)?;
         }
         OpCode::Constant(constant_index) => {
-          let constant = &chunk.constants[*constant_index];
-          self.stack.push_back(constant.clone());
+          let constant = self.frame().closure.function.chunk.constants[constant_index].clone();
+          self.push(constant)?;
         }
-        OpCode::Negate => match self.stack.pop_back().unwrap() {
-          Value::Number(number) => self.stack.push_back(Value::Number(-number)),
-          _ => panic!("Operand must be a number"),
+        OpCode::Negate => match self.pop()? {
+          Value::Number(number) => self.push(Value::Number(-number))?,
+          value => return Err(RuntimeErrorKind::OperandMustBeNumber(value)),
+        },
+        OpCode::Add => match self.stack.pop_numbers() {
+          Some((a, b)) => self.push(Value::Number(a + b))?,
+          None => match self.pop_operands()? {
+            (Value::String(a), Value::String(b)) => {
+              let mut string = String::with_capacity(a.len() + b.len());
+              string.push_str(&a);
+              string.push_str(&b);
+              self.push(Value::String(Rc::from(string)))?;
+            }
+            (a, b) => return Err(RuntimeErrorKind::OperandsMustBeNumbersOrStrings(a, b)),
+          },
         },
-        OpCode::Add => {
-          let b = self.stack.pop_back().unwrap();
-          let a = self.stack.pop_back().unwrap();
+        OpCode::Subtract => self.binary_number_operation(|a, b| Value::Number(a - b))?,
+        OpCode::Multiply => self.binary_number_operation(|a, b| Value::Number(a * b))?,
+        OpCode::Divide => self.binary_number_operation(|a, b| Value::Number(a / b))?,
+        OpCode::Greater => self.binary_number_operation(|a, b| Value::Boolean(a > b))?,
+        OpCode::GreaterEqual => self.binary_number_operation(|a, b| Value::Boolean(a >= b))?,
+        OpCode::Less => self.binary_number_operation(|a, b| Value::Boolean(a < b))?,
+        OpCode::LessEqual => self.binary_number_operation(|a, b| Value::Boolean(a <= b))?,
+        OpCode::Not => {
+          let value = self.pop()?;
+          self.push(Value::Boolean(value.is_falsey()))?;
+        }
+        OpCode::Equal => {
+          let (a, b) = self.pop_operands()?;
+          self.push(Value::Boolean(a == b))?;
+        }
+        OpCode::JumpIfFalse(offset) => {
+          if self.peek()?.is_falsey() {
+            self.frame_mut().ip += offset;
+          }
+        }
+        OpCode::Jump(offset) => self.frame_mut().ip += offset,
+        OpCode::Loop(offset) => self.frame_mut().ip -= offset,
+        OpCode::Call(argument_count) => {
+          let callee = self.peek_at(argument_count)?;
+          self.call_value(callee, argument_count)?;
+        }
+        OpCode::SetGlobal(slot) => {
+          let value = self.peek()?;
+
+          match self.globals.get_mut(slot) {
+            Some(Some(global_variable_value)) => *global_variable_value = value,
+            _ => return Err(self.undefined_variable(slot)),
+          }
+        }
+        OpCode::SetLocal(slot) => {
+          if self.stack.is_empty() {
+            return Err(RuntimeErrorKind::StackUnderflow);
+          }
+
+          self.stack.copy_top(slots_starts_at + slot);
+        }
+        OpCode::GetLocal(slot) => {
+          self.reserve_slot()?;
+          self.stack.push_copy(slots_starts_at + slot);
+        }
+        OpCode::GetUpvalue(index) => {
+          let value = match &*self.frame().closure.upvalues[index].borrow() {
+            Upvalue::Open(stack_index) => self.stack.get(*stack_index),
+            Upvalue::Closed(value) => value.clone(),
+          };
+
+          self.push(value)?;
+        }
+        OpCode::SetUpvalue(index) => {
+          let value = self.peek()?;
+
+          let upvalue = Rc::clone(&self.frame().closure.upvalues[index]);
+
+          let mut upvalue = upvalue.borrow_mut();
+
+          match &mut *upvalue {
+            Upvalue::Open(stack_index) => self.stack.set(*stack_index, value),
+            Upvalue::Closed(closed_value) => *closed_value = value,
+          }
+        }
+        OpCode::CloseUpvalue => {
+          self.close_upvalues(self.stack.len() - 1);
+          self.pop()?;
+        }
+        OpCode::Closure(index) => {
+          let function = match &self.frame().closure.function.chunk.constants[index] {
+            Value::Function(function) => Rc::clone(function),
+            value => return Err(RuntimeErrorKind::ExpectedFunction(value.clone())),
+          };
+
+          let upvalues = function
+            .upvalues
+            .iter()
+            .map(|upvalue| {
+              if upvalue.is_local {
+                self.capture_upvalue(slots_starts_at + upvalue.index)
+              } else {
+                Rc::clone(&self.frame().closure.upvalues[upvalue.index])
+              }
+            })
+            .collect();
+
+          self.push(Value::Closure(Rc::new(Closure { function, upvalues })))?;
+        }
+        OpCode::Class(index) => {
+          let name = self.read_identifier(index);
+
+          self.push(Value::Class(Rc::new(RefCell::new(Class {
+            name,
+            methods: HashMap::new(),
+          }))))?;
+        }
+        OpCode::Method(index) => {
+          let name = self.read_identifier(index);
+
+          let method = match self.pop()? {
+            Value::Closure(closure) => closure,
+            value => return Err(RuntimeErrorKind::ExpectedFunction(value)),
+          };
+
+          match self.peek()? {
+            Value::Class(class) => class.borrow_mut().methods.insert(name, method),
+            value => return Err(RuntimeErrorKind::ExpectedClass(value)),
+          };
+        }
+        OpCode::Inherit => {
+          let subclass = match self.pop()? {
+            Value::Class(class) => class,
+            value => return Err(RuntimeErrorKind::ExpectedClass(value)),
+          };
+
+          // Methods are copied down when the subclass is created,
+          // methods defined by the subclass overwrite them afterwards.
+          match self.peek()? {
+            Value::Class(superclass) => {
+              let methods = superclass.borrow().methods.clone();
+              subclass.borrow_mut().methods.extend(methods);
+            }
+            value => return Err(RuntimeErrorKind::SuperclassMustBeClass(value)),
+          }
+        }
+        OpCode::GetSuper(index) => {
+          let name = self.read_identifier(index);
+
+          let superclass = match self.pop()? {
+            Value::Class(class) => class,
+            value => return Err(RuntimeErrorKind::ExpectedClass(value)),
+          };
+
+          self.bind_method(&superclass, &name)?;
+        }
+        OpCode::BuildList(count) => {
+          if count > self.stack.len() {
+            return Err(RuntimeErrorKind::StackUnderflow);
+          }
+
+          let elements_start_at = self.stack.len() - count;
+
+          let elements = self.stack.drain(elements_start_at);
+
+          self.push(Value::List(Rc::new(RefCell::new(elements))))?;
+        }
+        OpCode::BuildMap(count) => {
+          if count * 2 > self.stack.len() {
+            return Err(RuntimeErrorKind::StackUnderflow);
+          }
+
+          let entries_start_at = self.stack.len() - count * 2;
 
-          match (a, b) {
-            (Value::Number(a), Value::Number(b)) => self.stack.push_back(Value::Number(a + b)),
-            _ => panic!("Operands must be numbers"),
+          let entries = self.stack.drain(entries_start_at);
+
+          let mut map = Map::new();
+
+          for entry in entries.chunks(2) {
+            map.insert(Vm::map_key(&entry[0])?, entry[1].clone());
+          }
+
+          self.push(Value::Map(Rc::new(RefCell::new(map))))?;
+        }
+        OpCode::Range => {
+          let end = self.pop()?;
+          let start = self.pop()?;
+
+          match (start, end) {
+            (Value::Number(start), Value::Number(end))
+              if start.fract() == 0.0 && end.fract() == 0.0 =>
+            {
+              self.push(Value::Range(start as i64, end as i64))?
+            }
+            (start, end) => return Err(RuntimeErrorKind::RangeBoundsMustBeIntegers(start, end)),
+          }
+        }
+        OpCode::GetIterator => {
+          let value = self.pop()?;
+
+          let iterator = self.iterator(value)?;
+
+          self.push(Value::Iterator(Rc::new(RefCell::new(iterator))))?;
+        }
+        OpCode::ForIter(offset) => {
+          let iterator = match self.peek()? {
+            Value::Iterator(iterator) => iterator,
+            value => return Err(RuntimeErrorKind::NotIterable(value)),
+          };
+
+          match self.iterator_next(&iterator)? {
+            Some(value) => self.push(value)?,
+            None => self.frame_mut().ip += offset,
           }
         }
-        OpCode::Subtract => {
-          let b = self.stack.pop_back().unwrap();
-          let a = self.stack.pop_back().unwrap();
+        OpCode::GetIndex => {
+          let index = self.pop()?;
+
+          let value = match self.pop()? {
+            Value::List(list) => {
+              let list = list.borrow();
+              list[Vm::list_index(&index, list.len())?].clone()
+            }
+            Value::Map(map) => match map.borrow().get(&Vm::map_key(&index)?) {
+              None => return Err(RuntimeErrorKind::KeyNotFound(index)),
+              Some(value) => value.clone(),
+            },
+            value => return Err(RuntimeErrorKind::NotIndexable(value)),
+          };
+
+          self.push(value)?;
+        }
+        OpCode::SetIndex => {
+          let value = self.pop()?;
+          let index = self.pop()?;
 
-          match (a, b) {
-            (Value::Number(a), Value::Number(b)) => self.stack.push_back(Value::Number(a - b)),
-            _ => panic!("Operands must be numbers"),
+          match self.pop()? {
+            Value::List(list) => {
+              let mut list = list.borrow_mut();
+              let position = Vm::list_index(&index, list.len())?;
+              list[position] = value.clone();
+            }
+            Value::Map(map) => {
+              let key = Vm::map_key(&index)?;
+              map.borrow_mut().insert(key, value.clone());
+            }
+            value => return Err(RuntimeErrorKind::NotIndexable(value)),
           }
+
+          self.push(value)?;
         }
-        OpCode::Multiply => {
-          let b = self.stack.pop_back().unwrap();
-          let a = self.stack.pop_back().unwrap();
+        OpCode::GetProperty(index) => {
+          let name = self.read_identifier(index);
 
-          match (a, b) {
-            (Value::Number(a), Value::Number(b)) => self.stack.push_back(Value::Number(a * b)),
-            _ => panic!("Operands must be numbers"),
+          let instance = match self.peek()? {
+            Value::Instance(instance) => instance,
+            value => return Err(RuntimeErrorKind::NotAnInstance(value)),
+          };
+
+          // Fields shadow methods with the same name.
+          let field = instance.borrow().fields.get(&name).cloned();
+
+          match field {
+            Some(value) => {
+              self.pop()?;
+              self.push(value)?;
+            }
+            None => self.bind_method(&instance.borrow().class, &name)?,
           }
         }
-        OpCode::Divide => {
-          let b = self.stack.pop_back().unwrap();
-          let a = self.stack.pop_back().unwrap();
+        OpCode::SetProperty(index) => {
+          let name = self.read_identifier(index);
+
+          let value = self.pop()?;
 
-          match (a, b) {
-            (Value::Number(a), Value::Number(b)) => self.stack.push_back(Value::Number(a / b)),
-            _ => panic!("Operands must be numbers"),
+          match self.pop()? {
+            Value::Instance(instance) => {
+              instance.borrow_mut().fields.insert(name, value.clone());
+            }
+            value => return Err(RuntimeErrorKind::NotAnInstance(value)),
           }
+
+          self.push(value)?;
         }
-        OpCode::Nil => self.stack.push_back(Value::Nil),
-        OpCode::Boolean(boolean) => self.stack.push_back(Value::Boolean(*boolean)),
+        OpCode::Nil => self.push(Value::Nil)?,
+        OpCode::Boolean(boolean) => self.push(Value::Boolean(boolean))?,
         OpCode::Print => {
-          println!("{:?}", self.stack.pop_back().unwrap());
+          let value = self.pop()?;
+          writeln!(self.output, "{}", value).map_err(|error| RuntimeErrorKind::Io(error.kind()))?;
         }
         OpCode::Pop => {
-          self.stack.pop_back();
+          self.pop()?;
         }
-        OpCode::DefineGlobalVariable(index) => match &chunk.constants[*index] {
-          Value::Identifier(global_variable_name) => {
-            let global_variable_value = self.stack.back().cloned().unwrap();
-            self
-              .globals
-              .insert(global_variable_name.clone(), global_variable_value);
-            self.stack.pop_back();
+        OpCode::DefineGlobal(slot) => {
+          let global_variable_value = self.pop()?;
+
+          // Slots are handed out at compile time, so the first
+          // definition of a global can be past the end.
+          if slot >= self.globals.len() {
+            self.globals.resize(slot + 1, None);
           }
-          value => panic!("expected global variable name, got {:?}", value),
-        },
-        OpCode::AccessGlobalVariable(index) => match &chunk.constants[*index] {
-          Value::Identifier(global_variable_name) => match self.globals.get(global_variable_name) {
-            None => {
-              return InterpretResult::RuntimeError(format!(
-                "undefined variable {}",
-                global_variable_name
-              ))
-            }
-            Some(value) => self.stack.push_back(value.clone()),
-          },
 
-          value => {
-            return InterpretResult::RuntimeError(format!(
-              "expected global variable identifier, got {:?}",
-              value
-            ))
+          self.globals[slot] = Some(global_variable_value);
+        }
+        OpCode::GetGlobal(slot) => match self.globals.get(slot) {
+          Some(Some(value)) => {
+            let value = value.clone();
+            self.push(value)?;
           }
+          _ => return Err(self.undefined_variable(slot)),
         },
       }
     }
-
-    return InterpretResult::Ok(self.stack.pop_back());
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::chunk::{Chunk, Location};
+  use crate::compiler::Compiler;
+  use crate::lexer;
+
+  use std::cell::RefCell;
+
+  #[derive(Clone, Default)]
+  struct Output(Rc<RefCell<Vec<u8>>>);
+
+  impl Write for Output {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+      self.0.borrow_mut().write(buffer)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+      Ok(())
+    }
+  }
+
+  /// Runs `source_code` and returns the result together with
+  /// everything written by `print` statements.
+  fn interpret(source_code: &str) -> (InterpretResult, String) {
+    let output = Output::default();
+
+    let mut vm = Vm::with_output(Box::new(output.clone()));
+
+    let tokens = lexer::lex(String::from(source_code)).unwrap();
+
+    let result = match Compiler::with_symbols(vm.symbols()).compile(tokens) {
+      Err(errors) => InterpretResult::CompileError(errors),
+      Ok(script) => vm.run(script),
+    };
+
+    let printed = String::from_utf8(output.0.borrow().clone()).unwrap();
+
+    (result, printed)
+  }
+
+  /// Creates a script where the instruction at offset `n` is at line `n + 1`.
+  fn script(code: Vec<OpCode>, constants: Vec<Value>) -> Function {
+    let mut chunk = Chunk::new();
+
+    chunk.constants = constants;
+
+    for (index, opcode) in code.into_iter().enumerate() {
+      chunk.write(
+        opcode,
+        Location {
+          line: index + 1,
+          column: 1,
+        },
+      );
+    }
+
+    Function {
+      name: None,
+      arity: 0,
+      chunk,
+      upvalues: Vec::new(),
+    }
+  }
+
+  fn script_stack_trace(line: usize) -> Vec<StackTraceEntry> {
+    vec![StackTraceEntry {
+      function: String::from("script"),
+      line,
+    }]
+  }
+
+  #[test]
+  fn arithmetic_opcodes_with_bad_operands_return_runtime_error() {
+    let test_cases: Vec<(OpCode, Value, Value)> = vec![
+      (OpCode::Subtract, Value::Number(1.0), Value::Boolean(true)),
+      (OpCode::Subtract, Value::Nil, Value::Number(2.0)),
+      (OpCode::Multiply, Value::Boolean(false), Value::Nil),
+      (OpCode::Divide, Value::Number(1.0), Value::Nil),
+      (
+        OpCode::Greater,
+        Value::String(Rc::from("a")),
+        Value::Number(1.0),
+      ),
+      (OpCode::Less, Value::Number(1.0), Value::Boolean(false)),
+      (OpCode::GreaterEqual, Value::Nil, Value::Number(1.0)),
+      (
+        OpCode::LessEqual,
+        Value::Number(1.0),
+        Value::String(Rc::from("a")),
+      ),
+    ];
+
+    for (opcode, a, b) in test_cases {
+      let mut vm = Vm::new();
+
+      let result = vm.run(script(
+        vec![OpCode::Constant(0), OpCode::Constant(1), opcode],
+        vec![a.clone(), b.clone()],
+      ));
+
+      match result {
+        InterpretResult::RuntimeError(error) => assert_eq!(
+          error,
+          RuntimeError {
+            kind: RuntimeErrorKind::OperandsMustBeNumbers(a, b),
+            offset: 2,
+            line: 3,
+            column: 1,
+            stack_trace: script_stack_trace(3),
+          }
+        ),
+        result => panic!("expected runtime error, got {:?}", result),
+      }
+
+      assert!(vm.stack.is_empty());
+    }
+  }
+
+  #[test]
+  fn negate_with_bad_operand_returns_runtime_error() {
+    let test_cases: Vec<Value> = vec![Value::Boolean(true), Value::Nil];
+
+    for operand in test_cases {
+      let mut vm = Vm::new();
+
+      let result = vm.run(script(
+        vec![OpCode::Nil, OpCode::Constant(0), OpCode::Negate],
+        vec![operand.clone()],
+      ));
+
+      match result {
+        InterpretResult::RuntimeError(error) => assert_eq!(
+          error,
+          RuntimeError {
+            kind: RuntimeErrorKind::OperandMustBeNumber(operand),
+            offset: 2,
+            line: 3,
+            column: 1,
+            stack_trace: script_stack_trace(3),
+          }
+        ),
+        result => panic!("expected runtime error, got {:?}", result),
+      }
+
+      assert!(vm.stack.is_empty());
+    }
+  }
+
+  #[test]
+  fn popping_an_empty_stack_returns_runtime_error() {
+    let test_cases: Vec<(Vec<OpCode>, usize)> = vec![
+      (vec![OpCode::Negate], 0),
+      (vec![OpCode::Add], 0),
+      (vec![OpCode::Nil, OpCode::Subtract], 1),
+      (vec![OpCode::Nil, OpCode::Multiply], 1),
+      (vec![OpCode::Divide], 0),
+      (vec![OpCode::Print], 0),
+      (vec![OpCode::Pop], 0),
+      (vec![OpCode::Not], 0),
+      (vec![OpCode::Nil, OpCode::Equal], 1),
+      (vec![OpCode::Nil, OpCode::Greater], 1),
+      (vec![OpCode::Less], 0),
+      (vec![OpCode::Return], 0),
+    ];
+
+    for (code, expected_offset) in test_cases {
+      let mut vm = Vm::new();
+
+      // Discard the script in slot 0 so the stack starts empty.
+      let code = std::iter::once(OpCode::Pop).chain(code).collect();
+
+      match vm.run(script(code, vec![])) {
+        InterpretResult::RuntimeError(error) => assert_eq!(
+          error,
+          RuntimeError {
+            kind: RuntimeErrorKind::StackUnderflow,
+            offset: expected_offset + 1,
+            line: expected_offset + 2,
+            column: 1,
+            stack_trace: script_stack_trace(expected_offset + 2),
+          }
+        ),
+        result => panic!("expected runtime error, got {:?}", result),
+      }
+
+      assert!(vm.stack.is_empty());
+    }
+  }
+
+  struct FailingOutput;
+
+  impl Write for FailingOutput {
+    fn write(&mut self, _buffer: &[u8]) -> io::Result<usize> {
+      Err(io::Error::from(io::ErrorKind::BrokenPipe))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+      Ok(())
+    }
+  }
+
+  #[test]
+  fn failing_to_write_output_returns_runtime_error() {
+    let mut vm = Vm::with_output(Box::new(FailingOutput));
+
+    let tokens = lexer::lex(String::from("print 1;")).unwrap();
+    let script = Compiler::with_symbols(vm.symbols())
+      .compile(tokens)
+      .unwrap();
+
+    match vm.run(script) {
+      InterpretResult::RuntimeError(error) => {
+        assert_eq!(RuntimeErrorKind::Io(io::ErrorKind::BrokenPipe), error.kind);
+        assert_eq!(
+          "unable to write output: broken pipe at line 1, column 1\n  in script at line 1",
+          error.to_string()
+        );
+      }
+      result => panic!("expected runtime error, got {:?}", result),
+    }
+  }
+
+  #[test]
+  fn vm_can_keep_running_after_a_runtime_error() {
+    let mut vm = Vm::new();
+
+    assert!(matches!(
+      vm.run(script(vec![OpCode::Boolean(true), OpCode::Negate], vec![])),
+      InterpretResult::RuntimeError(_)
+    ));
+
+    match vm.run(script(
+      vec![
+        OpCode::Constant(0),
+        OpCode::Constant(1),
+        OpCode::Add,
+        OpCode::Return,
+      ],
+      vec![Value::Number(1.0), Value::Number(2.0)],
+    )) {
+      InterpretResult::Ok(value) => assert_eq!(value, Some(Value::Number(3.0))),
+      result => panic!("expected ok, got {:?}", result),
+    }
+  }
+
+  #[test]
+  fn add_with_mismatched_operands_returns_runtime_error() {
+    let test_cases: Vec<(Value, Value)> = vec![
+      (Value::Number(1.0), Value::Boolean(true)),
+      (Value::String(Rc::from("a")), Value::Number(1.0)),
+      (Value::Number(1.0), Value::String(Rc::from("a"))),
+      (Value::Nil, Value::String(Rc::from("a"))),
+    ];
+
+    for (a, b) in test_cases {
+      let mut vm = Vm::new();
+
+      let result = vm.run(script(
+        vec![OpCode::Constant(0), OpCode::Constant(1), OpCode::Add],
+        vec![a.clone(), b.clone()],
+      ));
+
+      match result {
+        InterpretResult::RuntimeError(error) => assert_eq!(
+          error,
+          RuntimeError {
+            kind: RuntimeErrorKind::OperandsMustBeNumbersOrStrings(a, b),
+            offset: 2,
+            line: 3,
+            column: 1,
+            stack_trace: script_stack_trace(3),
+          }
+        ),
+        result => panic!("expected runtime error, got {:?}", result),
+      }
+    }
+  }
+
+  #[test]
+  fn strings() {
+    let test_cases: Vec<(&str, &str)> = vec![
+      (r#"print "hello";"#, "hello\n"),
+      (r#"print "hello" + " " + "world";"#, "hello world\n"),
+      (r#"print "" + "";"#, "\n"),
+      (
+        r#"let greeting = "hi"; print greeting + ", bob";"#,
+        "hi, bob\n",
+      ),
+    ];
+
+    for (input, expected_output) in test_cases {
+      let (result, output) = interpret(input);
+
+      assert!(matches!(result, InterpretResult::Ok(_)));
+      assert_eq!(expected_output, output);
+    }
+  }
+
+  #[test]
+  fn arithmetic_precedence_and_associativity() {
+    let test_cases: Vec<(&str, &str)> = vec![
+      ("print 1 - 2 - 3;", "-4\n"),
+      ("print 8 / 4 / 2;", "1\n"),
+      ("print 1 + 2 * 3;", "7\n"),
+      ("print (1 + 2) * 3;", "9\n"),
+      ("print -(1 + 2);", "-3\n"),
+      ("print --1;", "1\n"),
+    ];
+
+    for (input, expected_output) in test_cases {
+      let (result, output) = interpret(input);
+
+      assert!(matches!(result, InterpretResult::Ok(_)));
+      assert_eq!(expected_output, output);
+    }
+  }
+
+  #[test]
+  fn comparison_and_equality() {
+    let test_cases: Vec<(&str, &str)> = vec![
+      ("print 1 == 1;", "true\n"),
+      ("print 1 == 2;", "false\n"),
+      ("print 1 != 2;", "true\n"),
+      ("print nil == nil;", "true\n"),
+      ("print nil == false;", "false\n"),
+      ("print true != false;", "true\n"),
+      (r#"print "a" == "a";"#, "true\n"),
+      (r#"print "a" != "b";"#, "true\n"),
+      (r#"print "1" == 1;"#, "false\n"),
+      ("print 1 < 2;", "true\n"),
+      ("print 2 < 2;", "false\n"),
+      ("print 2 <= 2;", "true\n"),
+      ("print 3 <= 2;", "false\n"),
+      ("print 3 > 2;", "true\n"),
+      ("print 2 > 2;", "false\n"),
+      ("print 2 >= 2;", "true\n"),
+      ("print 1 >= 2;", "false\n"),
+      ("print 1 + 2 > 2 == true;", "true\n"),
+      // Every ordering comparison with NaN is false.
+      ("print (0 / 0) < 1;", "false\n"),
+      ("print (0 / 0) <= 1;", "false\n"),
+      ("print (0 / 0) > 1;", "false\n"),
+      ("print (0 / 0) >= 1;", "false\n"),
+      ("print 1 >= (0 / 0);", "false\n"),
+      ("print 1 <= (0 / 0);", "false\n"),
+      ("print (0 / 0) == (0 / 0);", "false\n"),
+      ("print (0 / 0) != (0 / 0);", "true\n"),
+    ];
+
+    for (input, expected_output) in test_cases {
+      let (result, output) = interpret(input);
+
+      assert!(matches!(result, InterpretResult::Ok(_)));
+      assert_eq!(expected_output, output);
+    }
+  }
+
+  #[test]
+  fn not_uses_truthiness() {
+    let test_cases: Vec<(&str, &str)> = vec![
+      ("print !true;", "false\n"),
+      ("print !false;", "true\n"),
+      ("print !nil;", "true\n"),
+      ("print !0;", "false\n"),
+      (r#"print !"";"#, "false\n"),
+      ("print !!nil;", "false\n"),
+      ("print !(1 + 2 > 3);", "true\n"),
+    ];
+
+    for (input, expected_output) in test_cases {
+      let (result, output) = interpret(input);
+
+      assert!(matches!(result, InterpretResult::Ok(_)));
+      assert_eq!(expected_output, output);
+    }
+  }
+
+  #[test]
+  fn if_else_statements() {
+    let test_cases: Vec<(&str, &str)> = vec![
+      ("if (true) print 1;", "1\n"),
+      ("if (false) print 1;", ""),
+      ("if (nil) print 1; else print 2;", "2\n"),
+      ("if (0) print 1; else print 2;", "1\n"),
+      (
+        "if (1 > 2) { print 1; } else { print 2; print 3; }",
+        "2\n3\n",
+      ),
+      (
+        "if (false) print 1; else if (true) print 2; else print 3;",
+        "2\n",
+      ),
+      ("if (true) { if (false) print 1; else print 2; }", "2\n"),
+      ("if (true) {} print 1;", "1\n"),
+    ];
+
+    for (input, expected_output) in test_cases {
+      let (result, output) = interpret(input);
+
+      assert!(matches!(result, InterpretResult::Ok(_)));
+      assert_eq!(expected_output, output);
+    }
+  }
+
+  #[test]
+  fn while_statement_with_false_condition_never_runs_its_body() {
+    let (result, output) = interpret("while (false) print 1; print 2;");
+
+    assert!(matches!(result, InterpretResult::Ok(_)));
+    assert_eq!("2\n", output);
+  }
+
+  #[test]
+  fn loop_jumps_back_until_condition_is_false() {
+    let output = Output::default();
+
+    let mut vm = Vm::with_output(Box::new(output.clone()));
+
+    let i = vm.symbols().borrow_mut().global_slot("i");
+
+    // let i = 0; while (i < 3) { print i; i = i + 1; }
+    let result = vm.run(script(
+      vec![
+        OpCode::Constant(0),
+        OpCode::DefineGlobal(i),
+        OpCode::GetGlobal(i),
+        OpCode::Constant(1),
+        OpCode::Less,
+        OpCode::JumpIfFalse(8),
+        OpCode::Pop,
+        OpCode::GetGlobal(i),
+        OpCode::Print,
+        OpCode::GetGlobal(i),
+        OpCode::Constant(2),
+        OpCode::Add,
+        OpCode::DefineGlobal(i),
+        OpCode::Loop(12),
+        OpCode::Pop,
+        OpCode::Nil,
+        OpCode::Return,
+      ],
+      vec![Value::Number(0.0), Value::Number(3.0), Value::Number(1.0)],
+    ));
+
+    assert!(matches!(result, InterpretResult::Ok(_)));
+    assert_eq!("0\n1\n2\n", String::from_utf8(output.0.take()).unwrap());
+  }
+
+  #[test]
+  fn local_variables() {
+    let test_cases: Vec<(&str, &str)> = vec![
+      ("{ let a = 1; print a; }", "1\n"),
+      ("{ let a = 1; let b = 2; print a + b; }", "3\n"),
+      ("let a = 1; { let a = 2; print a; } print a;", "2\n1\n"),
+      ("{ let a = 1; { let a = 2; print a; } print a; }", "2\n1\n"),
+      ("{ let a = 1; { let b = a + 1; print b; } }", "2\n"),
+      ("let a = 1; { let b = a; print b; }", "1\n"),
+      ("{ let a = 1; } { let b = 2; print b; }", "2\n"),
+      (
+        "{ let a = 1; if (a == 1) { let b = a + 1; print b; } }",
+        "2\n",
+      ),
+    ];
+
+    for (input, expected_output) in test_cases {
+      let (result, output) = interpret(input);
+
+      assert!(matches!(result, InterpretResult::Ok(_)));
+      assert_eq!(expected_output, output);
+    }
+  }
+
+  #[test]
+  fn locals_are_popped_when_their_scope_ends() {
+    let mut vm = Vm::new();
+
+    let tokens = lexer::lex(String::from("{ let a = 1; { let b = 2; let c = 3; } }")).unwrap();
+
+    let result = vm.run(
+      Compiler::with_symbols(vm.symbols())
+        .compile(tokens)
+        .unwrap(),
+    );
+
+    assert!(matches!(result, InterpretResult::Ok(_)));
+    assert!(vm.stack.is_empty());
+  }
+
+  #[test]
+  fn assignment() {
+    let test_cases: Vec<(&str, &str)> = vec![
+      ("let a = 1; a = 2; print a;", "2\n"),
+      ("let a = 1; print a = 2; print a;", "2\n2\n"),
+      (
+        "let a = 1; let b = 2; a = b = 3; print a; print b;",
+        "3\n3\n",
+      ),
+      ("{ let a = 1; a = a + 1; print a; }", "2\n"),
+      ("{ let a = 1; let b = 2; a = b = 3; print a + b; }", "6\n"),
+      ("let a = 1; { a = 2; } print a;", "2\n"),
+      ("let a = 1; { let a = 2; a = 3; } print a;", "1\n"),
+      (
+        "let i = 0; while (i < 3) { print i; i = i + 1; }",
+        "0\n1\n2\n",
+      ),
+    ];
+
+    for (input, expected_output) in test_cases {
+      let (result, output) = interpret(input);
+
+      assert!(matches!(result, InterpretResult::Ok(_)));
+      assert_eq!(expected_output, output);
+    }
+  }
+
+  #[test]
+  fn assigning_an_undeclared_global_returns_runtime_error() {
+    let (result, _output) = interpret("let a = 1;\na = 2;\nb = 3;");
+
+    match result {
+      InterpretResult::RuntimeError(error) => {
+        assert_eq!(
+          error.kind,
+          RuntimeErrorKind::UndefinedVariable(String::from("b"))
+        );
+        assert_eq!(error.line, 3);
+      }
+      result => panic!("expected runtime error, got {:?}", result),
+    }
+  }
+
+  #[test]
+  fn globals_are_resolved_when_they_are_used() {
+    let test_cases: Vec<(&str, &str)> = vec![
+      (
+        "fn f() { return g(); }\nfn g() { return 1; }\nprint f();",
+        "1\n",
+      ),
+      ("{ fn f() { print y; } }\nlet y = 2;", ""),
+    ];
+
+    for (input, expected_output) in test_cases {
+      let (result, output) = interpret(input);
+
+      assert!(matches!(result, InterpretResult::Ok(_)));
+      assert_eq!(expected_output, output);
+    }
+  }
+
+  #[test]
+  fn undefined_globals_are_reported_by_name() {
+    let test_cases: Vec<(&str, &str, usize)> = vec![
+      ("fn f() { return g(); }\nf();\nfn g() {}", "g", 1),
+      ("print x;", "x", 1),
+      ("print len([1]);\nprint x;", "x", 2),
+      // x has a slot from the moment it's compiled but is never defined.
+      ("if (false) { x = 1; }\nprint x;", "x", 2),
+    ];
+
+    for (input, name, line) in test_cases {
+      let (result, _output) = interpret(input);
+
+      match result {
+        InterpretResult::RuntimeError(error) => {
+          assert_eq!(
+            RuntimeErrorKind::UndefinedVariable(String::from(name)),
+            error.kind
+          );
+          assert_eq!(line, error.line);
+        }
+        result => panic!("expected runtime error, got {:?}", result),
+      }
+    }
+  }
+
+  #[test]
+  fn globals_persist_across_compilations() {
+    let output = Output::default();
+
+    let mut vm = Vm::with_output(Box::new(output.clone()));
+
+    let inputs = vec![
+      "let a = 1;",
+      "fn f() { return a + b; }",
+      "let b = 2;",
+      "print f();",
+      "a = 3; print f();",
+    ];
+
+    for input in inputs {
+      let tokens = lexer::lex(String::from(input)).unwrap();
+
+      let script = Compiler::with_symbols(vm.symbols())
+        .compile(tokens)
+        .unwrap();
+
+      assert!(matches!(vm.run(script), InterpretResult::Ok(_)));
+    }
+
+    assert_eq!("3\n5\n", String::from_utf8(output.0.take()).unwrap());
+  }
+
+  #[test]
+  fn functions() {
+    let test_cases: Vec<(&str, &str)> = vec![
+      ("fn f() { print 1; } f();", "1\n"),
+      ("fn f() { print 1; } print f;", "<fn f>\n"),
+      ("fn f() {} print f();", "nil\n"),
+      ("fn f() { return; } print f();", "nil\n"),
+      ("fn add(a, b) { return a + b; } print add(1, 2);", "3\n"),
+      (
+        "fn f(a, b, c) { print a; print b; print c; } f(1, 2, 3);",
+        "1\n2\n3\n",
+      ),
+      (
+        "fn fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); } print fib(10);",
+        "55\n",
+      ),
+      (
+        "fn f() { let a = 1; { let b = 2; return a + b; } } print f() + f();",
+        "6\n",
+      ),
+      (
+        "{ fn double(n) { return n * 2; } print double(double(2)); }",
+        "8\n",
+      ),
+      (
+        "fn outer() { fn inner(x) { return x + 1; } return inner(1); } print outer();",
+        "2\n",
+      ),
+      (
+        "fn f(x) { x = x + 1; return x; } let a = 1; print f(a); print a;",
+        "2\n1\n",
+      ),
+      ("fn f() { return f; } print f()() == f;", "true\n"),
+    ];
+
+    for (input, expected_output) in test_cases {
+      let (result, output) = interpret(input);
+
+      assert!(matches!(result, InterpretResult::Ok(_)));
+      assert_eq!(expected_output, output);
+    }
+  }
+
+  #[test]
+  fn calling_non_functions_returns_runtime_error() {
+    let test_cases: Vec<(&str, Value)> = vec![
+      ("1();", Value::Number(1.0)),
+      ("nil();", Value::Nil),
+      (r#""f"();"#, Value::String(Rc::from("f"))),
+      ("let a = true; a();", Value::Boolean(true)),
+    ];
+
+    for (input, expected_callee) in test_cases {
+      match interpret(input) {
+        (InterpretResult::RuntimeError(error), _) => {
+          assert_eq!(RuntimeErrorKind::NotCallable(expected_callee), error.kind)
+        }
+        (result, _) => panic!("expected runtime error, got {:?}", result),
+      }
+    }
+  }
+
+  #[test]
+  fn calling_functions_with_wrong_number_of_arguments_returns_runtime_error() {
+    let test_cases: Vec<(&str, usize, usize)> = vec![
+      ("fn f() {} f(1);", 0, 1),
+      ("fn f(a) {} f();", 1, 0),
+      ("fn f(a, b) {} f(1, 2, 3);", 2, 3),
+    ];
+
+    for (input, expected, got) in test_cases {
+      match interpret(input) {
+        (InterpretResult::RuntimeError(error), _) => assert_eq!(
+          RuntimeErrorKind::ArityMismatch { expected, got },
+          error.kind
+        ),
+        (result, _) => panic!("expected runtime error, got {:?}", result),
+      }
+    }
+  }
+
+  #[test]
+  fn runtime_errors_contain_a_stack_trace() {
+    let (result, _output) = interpret(
+      "fn inner() {
+  return -nil;
+}
+
+fn outer() {
+  inner();
+}
+
+outer();",
+    );
+
+    match result {
+      InterpretResult::RuntimeError(error) => {
+        assert_eq!(
+          RuntimeErrorKind::OperandMustBeNumber(Value::Nil),
+          error.kind
+        );
+        assert_eq!(2, error.line);
+        assert_eq!(10, error.column);
+        assert_eq!(
+          vec![
+            StackTraceEntry {
+              function: String::from("inner"),
+              line: 2,
+            },
+            StackTraceEntry {
+              function: String::from("outer"),
+              line: 6,
+            },
+            StackTraceEntry {
+              function: String::from("script"),
+              line: 9,
+            },
+          ],
+          error.stack_trace
+        );
+        assert_eq!(
+          "operand must be a number, got Nil at line 2, column 10
+  in inner at line 2
+  in outer at line 6
+  in script at line 9",
+          error.to_string()
+        );
+      }
+      result => panic!("expected runtime error, got {:?}", result),
+    }
+  }
+
+  #[test]
+  fn unbounded_recursion_returns_stack_overflow() {
+    let (result, _output) = interpret("fn f() { f(); } f();");
+
+    match result {
+      InterpretResult::RuntimeError(error) => {
+        assert_eq!(RuntimeErrorKind::StackOverflow, error.kind);
+        assert_eq!(MAX_FRAMES, error.stack_trace.len());
+      }
+      result => panic!("expected runtime error, got {:?}", result),
+    }
+  }
+
+  /// A script that builds a list with `length` elements.
+  fn list_of_length(length: usize) -> String {
+    format!("print len([{}]);", vec!["0"; length].join(", "))
+  }
+
+  #[test]
+  fn pushing_past_the_stack_max_returns_stack_overflow() {
+    let test_cases: Vec<(usize, String, Option<usize>)> = vec![
+      // The script itself takes the first slot, so it can't even start.
+      (0, String::from("print 1;"), Some(0)),
+      (4, String::from("print 1 + 2;"), None),
+      (4, String::from("print [1, 2, 3];"), None),
+      (4, String::from("print [1, 2, 3, 4];"), Some(1)),
+      (
+        8,
+        String::from("fn f(a, b) { return a + b; }\nprint f(1, f(2, f(3, 4)));"),
+        Some(1),
+      ),
+      // The stack grows past its initial capacity up to the limit.
+      (
+        STACK_INITIAL_CAPACITY * 2,
+        list_of_length(STACK_INITIAL_CAPACITY * 2 - 2),
+        None,
+      ),
+      (
+        STACK_INITIAL_CAPACITY * 2,
+        list_of_length(STACK_INITIAL_CAPACITY * 2),
+        Some(1),
+      ),
+      (usize::MAX, list_of_length(1000), None),
+    ];
+
+    for (stack_max, input, expected_line) in test_cases {
+      let mut vm = Vm::with_stack_max(Box::new(io::sink()), stack_max);
+
+      assert!(vm.stack.capacity() <= STACK_INITIAL_CAPACITY);
+
+      let tokens = lexer::lex(input.clone()).unwrap();
+
+      let result = vm.run(
+        Compiler::with_symbols(vm.symbols())
+          .compile(tokens)
+          .unwrap(),
+      );
+
+      match (result, expected_line) {
+        (InterpretResult::Ok(_), None) => {}
+        (InterpretResult::RuntimeError(error), Some(line)) => {
+          assert_eq!(RuntimeErrorKind::StackOverflow, error.kind);
+          assert_eq!(line, error.line);
+        }
+        (result, _) => panic!("unexpected result for {}: {:?}", input, result),
+      }
+
+      assert!(vm.stack.is_empty());
+    }
+  }
+
+  #[test]
+  fn errors_before_the_script_starts_have_no_location() {
+    let mut vm = Vm::with_stack_max(Box::new(io::sink()), 0);
+
+    let tokens = lexer::lex(String::from("print 1;")).unwrap();
+
+    let result = vm.run(
+      Compiler::with_symbols(vm.symbols())
+        .compile(tokens)
+        .unwrap(),
+    );
+
+    match result {
+      InterpretResult::RuntimeError(error) => {
+        assert!(error.stack_trace.is_empty());
+        assert_eq!("stack overflow", error.to_string());
+      }
+      result => panic!("expected runtime error, got {:?}", result),
+    }
+  }
+
+  #[test]
+  fn closures() {
+    let test_cases: Vec<(&str, &str)> = vec![
+      (
+        "fn counter() {
+          let count = 0;
+          fn increment() { count = count + 1; return count; }
+          return increment;
+        }
+        let a = counter();
+        let b = counter();
+        print a(); print a(); print b(); print a();",
+        "1\n2\n1\n3\n",
+      ),
+      (
+        "fn outer() {
+          let x = \"outside\";
+          fn inner() { print x; }
+          return inner;
+        }
+        outer()();",
+        "outside\n",
+      ),
+      (
+        "let get = nil;
+        let set = nil;
+        fn make() {
+          let shared = 1;
+          fn getter() { return shared; }
+          fn setter(value) { shared = value; }
+          get = getter;
+          set = setter;
+        }
+        make();
+        print get();
+        set(2);
+        print get();",
+        "1\n2\n",
+      ),
+      (
+        "fn outer() {
+          let x = 1;
+          fn middle() {
+            fn inner() { x = x + 1; return x; }
+            return inner;
+          }
+          return middle;
+        }
+        let inner = outer()();
+        print inner(); print inner();",
+        "2\n3\n",
+      ),
+      (
+        "let f = nil;
+        {
+          let a = 1;
+          fn g() { return a; }
+          f = g;
+          a = 2;
+        }
+        print f();",
+        "2\n",
+      ),
+      (
+        "{
+          let first = nil;
+          let i = 0;
+          while (i < 2) {
+            let j = i;
+            fn capture() { return j; }
+            if (i == 0) first = capture;
+            i = i + 1;
+          }
+          print first();
+        }",
+        "0\n",
+      ),
+      (
+        "fn f(a) { fn g() { return a; } return g; } print f(1)() + f(2)();",
+        "3\n",
+      ),
+    ];
+
+    for (input, expected_output) in test_cases {
+      let (result, output) = interpret(input);
+
+      assert!(matches!(result, InterpretResult::Ok(_)));
+      assert_eq!(expected_output, output);
+    }
+  }
+
+  #[test]
+  fn captured_variables_are_closed_when_they_leave_the_stack() {
+    let mut vm = Vm::new();
+
+    let tokens = lexer::lex(String::from(
+      "fn outer() { let a = 1; fn inner() { return a; } return inner; } let f = outer();",
+    ))
+    .unwrap();
+
+    vm.run(
+      Compiler::with_symbols(vm.symbols())
+        .compile(tokens)
+        .unwrap(),
+    );
+
+    assert!(vm.open_upvalues.is_empty());
+    assert!(vm.stack.is_empty());
+  }
+
+  #[test]
+  fn classes() {
+    let test_cases: Vec<(&str, &str)> = vec![
+      ("class A {} print A;", "A\n"),
+      ("class A {} print A();", "A instance\n"),
+      ("class A {} let a = A(); a.x = 1; print a.x;", "1\n"),
+      ("class A {} let a = A(); print a.x = 2;", "2\n"),
+      (
+        "class A {} let a = A(); a.x = 1; a.x = a.x + 1; print a.x;",
+        "2\n",
+      ),
+      (
+        r#"class A { greet() { print "hello"; } } A().greet();"#,
+        "hello\n",
+      ),
+      (
+        "class A { get() { return this.x; } } let a = A(); a.x = 3; print a.get();",
+        "3\n",
+      ),
+      (
+        "class Point { init(x, y) { this.x = x; this.y = y; } sum() { return this.x + this.y; } }
+        print Point(1, 2).sum();",
+        "3\n",
+      ),
+      (
+        "class A { init() { this.x = 1; return; this.x = 2; } } print A().x;",
+        "1\n",
+      ),
+      ("class A { init() {} } let a = A(); print a.init() == a;", "true\n"),
+      (
+        "class A { init(x) { this.x = x; } get() { return this.x; } }
+        let get = A(4).get; print get();",
+        "4\n",
+      ),
+      (
+        "class A { f() { fn g() { return this.x; } return g; } } let a = A(); a.x = 5; print a.f()();",
+        "5\n",
+      ),
+      (
+        "fn f() { return 1; } class A {} let a = A(); a.f = f; print a.f();",
+        "1\n",
+      ),
+      ("{ class A { f() { return 6; } } print A().f(); }", "6\n"),
+      ("class A {} let a = A(); print a == a; print a == A();", "true\nfalse\n"),
+    ];
+
+    for (input, expected_output) in test_cases {
+      let (result, output) = interpret(input);
+
+      assert!(matches!(result, InterpretResult::Ok(_)));
+      assert_eq!(expected_output, output);
+    }
+  }
+
+  #[test]
+  fn invalid_property_accesses_return_runtime_error() {
+    let test_cases: Vec<(&str, RuntimeErrorKind)> = vec![
+      (
+        "class A {} print A().x;",
+        RuntimeErrorKind::UndefinedProperty(String::from("x")),
+      ),
+      (
+        "class A {} A().f();",
+        RuntimeErrorKind::UndefinedProperty(String::from("f")),
+      ),
+      (
+        "print 1.x;",
+        RuntimeErrorKind::NotAnInstance(Value::Number(1.0)),
+      ),
+      (
+        "let a = nil; a.x = 1;",
+        RuntimeErrorKind::NotAnInstance(Value::Nil),
+      ),
+      (
+        "class A {} A(1);",
+        RuntimeErrorKind::ArityMismatch {
+          expected: 0,
+          got: 1,
+        },
+      ),
+      (
+        "class A { init(a) {} } A();",
+        RuntimeErrorKind::ArityMismatch {
+          expected: 1,
+          got: 0,
+        },
+      ),
+    ];
+
+    for (input, expected_kind) in test_cases {
+      match interpret(input) {
+        (InterpretResult::RuntimeError(error), _) => assert_eq!(expected_kind, error.kind),
+        (result, _) => panic!("expected runtime error, got {:?}", result),
+      }
+    }
+  }
+
+  #[test]
+  fn inheritance() {
+    let test_cases: Vec<(&str, &str)> = vec![
+      (
+        "class A { f() { return 1; } } class B < A {} print B().f();",
+        "1\n",
+      ),
+      (
+        "class A { f() { return 1; } } class B < A { f() { return 2; } } print B().f(); print A().f();",
+        "2\n1\n",
+      ),
+      (
+        "class A { f() { return 1; } } class B < A { f() { return super.f() + 1; } } print B().f();",
+        "2\n",
+      ),
+      (
+        "class A { init(x) { this.x = x; } } class B < A { init(x, y) { super.init(x); this.y = y; } }
+        let b = B(1, 2); print b.x + b.y;",
+        "3\n",
+      ),
+      (
+        "class A { name() { return \"A\"; } describe() { return this.name(); } }
+        class B < A { name() { return \"B\"; } }
+        print B().describe();",
+        "B\n",
+      ),
+      (
+        "class A { f() { return 1; } } class B < A { f() { return 2; } }
+        class C < B { f() { return super.f() * 10; } } print C().f();",
+        "20\n",
+      ),
+      (
+        "class A { f() { return this.x; } } class B < A { g() { let f = super.f; return f; } }
+        let b = B(); b.x = 4; print b.g()();",
+        "4\n",
+      ),
+      (
+        "class A { f() { return 5; } }
+        class B < A { g() { fn h() { return super.f(); } return h; } } print B().g()();",
+        "5\n",
+      ),
+      (
+        "{ class A { f() { return 6; } } class B < A { f() { return super.f(); } } print B().f(); }",
+        "6\n",
+      ),
+    ];
+
+    for (input, expected_output) in test_cases {
+      let (result, output) = interpret(input);
+
+      assert!(matches!(result, InterpretResult::Ok(_)));
+      assert_eq!(expected_output, output);
+    }
+  }
+
+  #[test]
+  fn inheriting_from_non_classes_returns_runtime_error() {
+    let test_cases: Vec<(&str, Value)> = vec![
+      ("let A = 1; class B < A {}", Value::Number(1.0)),
+      ("let A = nil; class B < A {}", Value::Nil),
+      (
+        r#"let A = "A"; class B < A {}"#,
+        Value::String(Rc::from("A")),
+      ),
+    ];
+
+    for (input, expected_superclass) in test_cases {
+      match interpret(input) {
+        (InterpretResult::RuntimeError(error), _) => assert_eq!(
+          RuntimeErrorKind::SuperclassMustBeClass(expected_superclass),
+          error.kind
+        ),
+        (result, _) => panic!("expected runtime error, got {:?}", result),
+      }
+    }
+  }
+
+  #[test]
+  fn calling_undefined_super_methods_returns_runtime_error() {
+    let (result, _output) =
+      interpret("class A {} class B < A { f() { return super.f(); } } B().f();");
+
+    match result {
+      InterpretResult::RuntimeError(error) => assert_eq!(
+        RuntimeErrorKind::UndefinedProperty(String::from("f")),
+        error.kind
+      ),
+      result => panic!("expected runtime error, got {:?}", result),
+    }
+  }
+
+  #[test]
+  fn lists() {
+    let test_cases: Vec<(&str, &str)> = vec![
+      ("print [];", "[]\n"),
+      ("print [1, 2, 3];", "[1, 2, 3]\n"),
+      (r#"print [1, "a", nil, [true]];"#, "[1, a, nil, [true]]\n"),
+      ("let xs = [1, 2, 3]; print xs[0]; print xs[2];", "1\n3\n"),
+      ("let xs = [1, 2, 3]; print xs[-1]; print xs[-3];", "3\n1\n"),
+      ("let xs = [1, 2, 3]; print xs[1 + 1];", "3\n"),
+      ("let xs = [1, 2, 3]; xs[0] = 4; print xs;", "[4, 2, 3]\n"),
+      ("let xs = [1, 2, 3]; xs[-1] = 4; print xs;", "[1, 2, 4]\n"),
+      ("let xs = [1]; print xs[0] = 2;", "2\n"),
+      (
+        "let xs = [[1, 2], [3, 4]]; xs[1][0] = 5; print xs[1][0];",
+        "5\n",
+      ),
+      ("let xs = [1]; let ys = xs; ys[0] = 2; print xs[0];", "2\n"),
+      (
+        "let xs = [1]; print xs == xs; print [1] == [1];",
+        "true\nfalse\n",
+      ),
+      ("fn f() { return [1, 2]; } print f()[1];", "2\n"),
+      (
+        "class A { init() { this.xs = [1]; } } let a = A(); a.xs[0] = 3; print a.xs;",
+        "[3]\n",
+      ),
+      ("print len([]); print len([1, 2, 3]);", "0\n3\n"),
+      (r#"print len("hello");"#, "5\n"),
+      ("print len;", "<native fn len>\n"),
+      ("let xs = [1]; xs[0] = xs; print xs;", "[[...]]\n"),
+      (
+        "let xs = [1]; let ys = [xs, xs]; xs[0] = ys; print ys;",
+        "[[[...]], [[...]]]\n",
+      ),
+      // The same list twice side by side isn't a cycle.
+      ("let xs = [1]; print [xs, xs];", "[[1], [1]]\n"),
+    ];
+
+    for (input, expected_output) in test_cases {
+      let (result, output) = interpret(input);
+
+      assert!(matches!(result, InterpretResult::Ok(_)));
+      assert_eq!(expected_output, output);
+    }
+  }
+
+  #[test]
+  fn invalid_list_operations_return_runtime_error() {
+    let test_cases: Vec<(&str, RuntimeErrorKind, usize)> = vec![
+      (
+        "let xs = [1, 2, 3];\nprint xs[3];",
+        RuntimeErrorKind::IndexOutOfBounds {
+          index: 3,
+          length: 3,
+        },
+        2,
+      ),
+      (
+        "let xs = [1, 2, 3];\n\nxs[-4] = 1;",
+        RuntimeErrorKind::IndexOutOfBounds {
+          index: -4,
+          length: 3,
+        },
+        3,
+      ),
+      (
+        "print [][0];",
+        RuntimeErrorKind::IndexOutOfBounds {
+          index: 0,
+          length: 0,
+        },
+        1,
+      ),
+      (
+        "print [1][0.5];",
+        RuntimeErrorKind::IndexMustBeInteger(Value::Number(0.5)),
+        1,
+      ),
+      (
+        r#"print [1]["a"];"#,
+        RuntimeErrorKind::IndexMustBeInteger(Value::String(Rc::from("a"))),
+        1,
+      ),
+      (
+        "let a = 1; print a[0];",
+        RuntimeErrorKind::NotIndexable(Value::Number(1.0)),
+        1,
+      ),
+      (
+        "len(1);",
+        RuntimeErrorKind::InvalidArgument {
+          function: String::from("len"),
+          argument: Value::Number(1.0),
+        },
+        1,
+      ),
+      (
+        "len([], []);",
+        RuntimeErrorKind::ArityMismatch {
+          expected: 1,
+          got: 2,
+        },
+        1,
+      ),
+    ];
+
+    for (input, expected_kind, expected_line) in test_cases {
+      match interpret(input) {
+        (InterpretResult::RuntimeError(error), _) => {
+          assert_eq!(expected_kind, error.kind);
+          assert_eq!(expected_line, error.line);
+        }
+        (result, _) => panic!("expected runtime error, got {:?}", result),
+      }
+    }
+  }
+
+  #[test]
+  fn maps() {
+    let test_cases: Vec<(&str, &str)> = vec![
+      ("print {};", "{}\n"),
+      (r#"print {"a": 1, "b": 2};"#, "{a: 1, b: 2}\n"),
+      (r#"let m = {"a": 1}; print m["a"];"#, "1\n"),
+      (r#"let m = {}; m["a"] = 1; m["b"] = 2; print m;"#, "{a: 1, b: 2}\n"),
+      (r#"let m = {"a": 1}; m["a"] = 2; print m;"#, "{a: 2}\n"),
+      (r#"let m = {"a": 1, "a": 2}; print m;"#, "{a: 2}\n"),
+      (r#"let m = {}; print m["a"] = 3;"#, "3\n"),
+      (
+        "let m = {1: \"one\", true: \"yes\", nil: \"none\"}; print m[1]; print m[true]; print m[nil];",
+        "one\nyes\nnone\n",
+      ),
+      ("let m = {0: 1}; print m[-0];", "1\n"),
+      ("let m = {1: 1}; print m[0.5 + 0.5];", "1\n"),
+      (r#"let m = {"ab": 1}; print m["a" + "b"];"#, "1\n"),
+      (r#"let m = {"a": [1, 2]}; m["a"][0] = 3; print m;"#, "{a: [3, 2]}\n"),
+      (
+        r#"let m = {"c": 1, "a": 2}; m["b"] = 3; print keys(m);"#,
+        "[c, a, b]\n",
+      ),
+      (r#"print len({}); print len({"a": 1, "b": 2});"#, "0\n2\n"),
+      ("let m = {}; print m == m; print {} == {};", "true\nfalse\n"),
+      (r#"{ let m = {"a": 1}; print m["a"]; }"#, "1\n"),
+      (r#"let m = {}; m["self"] = m; print m;"#, "{self: {...}}\n"),
+      (
+        r#"let m = {}; let xs = [m]; m["xs"] = xs; print xs;"#,
+        "[{xs: [...]}]\n",
+      ),
+    ];
+
+    for (input, expected_output) in test_cases {
+      let (result, output) = interpret(input);
+
+      assert!(matches!(result, InterpretResult::Ok(_)));
+      assert_eq!(expected_output, output);
+    }
+  }
+
+  #[test]
+  fn invalid_map_operations_return_runtime_error() {
+    let test_cases: Vec<(&str, RuntimeErrorKind)> = vec![
+      (
+        r#"let m = {}; print m["a"];"#,
+        RuntimeErrorKind::KeyNotFound(Value::String(Rc::from("a"))),
+      ),
+      (
+        "keys(1);",
+        RuntimeErrorKind::InvalidArgument {
+          function: String::from("keys"),
+          argument: Value::Number(1.0),
+        },
+      ),
+    ];
+
+    for (input, expected_kind) in test_cases {
+      match interpret(input) {
+        (InterpretResult::RuntimeError(error), _) => assert_eq!(expected_kind, error.kind),
+        (result, _) => panic!("expected runtime error, got {:?}", result),
+      }
+    }
+  }
+
+  #[test]
+  fn unhashable_map_keys_return_runtime_error() {
+    // Objects and NaN are never equal to each other, so only the kind is checked.
+    let test_cases: Vec<&str> = vec![
+      "let m = {}; m[[]] = 1;",
+      "let m = {}; print m[{}];",
+      "print {0 / 0: 1};",
+      "class A {} print {A(): 1};",
+      "fn f() {} let m = {}; m[f] = 1;",
+    ];
+
+    for input in test_cases {
+      match interpret(input) {
+        (InterpretResult::RuntimeError(error), _) => {
+          assert!(matches!(error.kind, RuntimeErrorKind::UnhashableKey(_)))
+        }
+        (result, _) => panic!("expected runtime error, got {:?}", result),
+      }
+    }
+  }
+
+  #[test]
+  fn logical_operators() {
+    let test_cases: Vec<(&str, &str)> = vec![
+      ("print true and false;", "false\n"),
+      ("print true and true;", "true\n"),
+      ("print false or true;", "true\n"),
+      ("print false or false;", "false\n"),
+      ("print 1 and 2;", "2\n"),
+      ("print nil and 2;", "nil\n"),
+      (r#"print nil or "default";"#, "default\n"),
+      ("print 1 or 2;", "1\n"),
+      ("print false or nil;", "nil\n"),
+      // and binds tighter than or.
+      ("print true or false and false;", "true\n"),
+      ("print (true or false) and false;", "false\n"),
+      ("print 1 < 2 and 2 < 3;", "true\n"),
+      ("print 1 == 2 or 2 == 2;", "true\n"),
+      (
+        "let a = 1; if (a > 0 and a < 2) print \"in\"; else print \"out\";",
+        "in\n",
+      ),
+      ("let a = 3; if (a < 0 or a > 2) print \"out\";", "out\n"),
+      ("let a = nil; a = a or 5; print a;", "5\n"),
+      ("{ let a = false; let b = a and 1; print b; }", "false\n"),
+    ];
+
+    for (input, expected_output) in test_cases {
+      let (result, output) = interpret(input);
+
+      assert!(matches!(result, InterpretResult::Ok(_)));
+      assert_eq!(expected_output, output);
+    }
+  }
+
+  #[test]
+  fn logical_operators_short_circuit() {
+    let test_cases: Vec<(&str, &str)> = vec![
+      (
+        "fn f() { print \"called\"; return true; } print false and f();",
+        "false\n",
+      ),
+      (
+        "fn f() { print \"called\"; return true; } print true or f();",
+        "true\n",
+      ),
+      (
+        "fn f() { print \"called\"; return true; } print true and f();",
+        "called\ntrue\n",
+      ),
+      (
+        "fn f() { print \"called\"; return true; } print false or f();",
+        "called\ntrue\n",
+      ),
+      (
+        "let calls = 0; fn f() { calls = calls + 1; return false; }
+        print f() and f() and f(); print calls;",
+        "false\n1\n",
+      ),
+      // Would fail with a runtime error if the right operand was evaluated.
+      ("print nil and nil();", "nil\n"),
+      ("print 1 or 1 + nil;", "1\n"),
+    ];
+
+    for (input, expected_output) in test_cases {
+      let (result, output) = interpret(input);
+
+      assert!(matches!(result, InterpretResult::Ok(_)));
+      assert_eq!(expected_output, output);
+    }
+  }
+
+  #[test]
+  fn for_loops() {
+    let test_cases: Vec<(&str, &str)> = vec![
+      ("for (let i = 0; i < 3; i = i + 1) print i;", "0\n1\n2\n"),
+      ("let i = 0; for (; i < 2; i = i + 1) print i; print i;", "0\n1\n2\n"),
+      ("let i = 0; for (i = 5; i < 7; i = i + 1) print i;", "5\n6\n"),
+      ("for (let i = 0; i < 2;) { print i; i = i + 1; }", "0\n1\n"),
+      ("for (let i = 3; i > 0; i = i - 1) {} print \"done\";", "done\n"),
+      ("for (let i = 0; false; i = i + 1) print i; print \"skipped\";", "skipped\n"),
+      (
+        "fn f() { let total = 0; for (let i = 1; i <= 4; i = i + 1) total = total + i; return total; } print f();",
+        "10\n",
+      ),
+      (
+        "for (let i = 0; i < 2; i = i + 1) for (let j = 0; j < 2; j = j + 1) print i * 10 + j;",
+        "0\n1\n10\n11\n",
+      ),
+      // The loop variable goes out of scope after the loop.
+      ("let i = \"global\"; for (let i = 0; i < 1; i = i + 1) {} print i;", "global\n"),
+    ];
+
+    for (input, expected_output) in test_cases {
+      let (result, output) = interpret(input);
+
+      assert!(matches!(result, InterpretResult::Ok(_)));
+      assert_eq!(expected_output, output);
+    }
+  }
+
+  #[test]
+  fn break_and_continue() {
+    let test_cases: Vec<(&str, &str)> = vec![
+      ("for (;;) { print 1; break; } print 2;", "1\n2\n"),
+      (
+        "for (let i = 0; i < 10; i = i + 1) { if (i == 3) break; print i; }",
+        "0\n1\n2\n",
+      ),
+      (
+        "for (let i = 0; i < 4; i = i + 1) { if (i == 1 or i == 2) continue; print i; }",
+        "0\n3\n",
+      ),
+      (
+        "let i = 0; while (i < 5) { i = i + 1; if (i == 2) continue; if (i == 4) break; print i; } print i;",
+        "1\n3\n4\n",
+      ),
+      (
+        "let i = 0; while (true) { let a = 1; { let b = 2; if (i == 2) break; } i = i + 1; } print i;",
+        "2\n",
+      ),
+      (
+        "for (let i = 0; i < 3; i = i + 1) { let a = i * 2; { let b = a + 1; if (b == 3) continue; print b; } }",
+        "1\n5\n",
+      ),
+      // Only the innermost loop is left.
+      (
+        "for (let i = 0; i < 2; i = i + 1) { for (let j = 0; j < 5; j = j + 1) { if (j == 1) break; print i * 10 + j; } }",
+        "0\n10\n",
+      ),
+      (
+        "fn f() { let a = 1; while (true) { let b = 2; let c = 3; break; } return a; } print f();",
+        "1\n",
+      ),
+      // Captured locals are closed when break leaves their scope.
+      (
+        "let f = nil; while (true) { let a = 1; fn g() { return a; } f = g; break; } print f();",
+        "1\n",
+      ),
+      (
+        "let fs = []; fs = [nil, nil]; let i = 0;
+        while (i < 2) { let j = i; fn g() { return j; } fs[i] = g; i = i + 1; continue; }
+        print fs[0](); print fs[1]();",
+        "0\n1\n",
+      ),
+    ];
+
+    for (input, expected_output) in test_cases {
+      let (result, output) = interpret(input);
+
+      assert!(matches!(result, InterpretResult::Ok(_)));
+      assert_eq!(expected_output, output);
+    }
+  }
+
+  #[test]
+  fn for_in_loops() {
+    let test_cases: Vec<(&str, &str)> = vec![
+      ("for x in [1, 2, 3] print x;", "1\n2\n3\n"),
+      ("for x in [] print x; print \"empty\";", "empty\n"),
+      (
+        r#"for key in {"b": 1, "a": 2, "c": 3} print key;"#,
+        "b\na\nc\n",
+      ),
+      (
+        r#"let m = {"a": 1, "b": 2}; for key in m print m[key];"#,
+        "1\n2\n",
+      ),
+      (r#"for c in "abc" print c;"#, "a\nb\nc\n"),
+      (r#"for c in "héllo" print c;"#, "h\né\nl\nl\no\n"),
+      ("for i in 0..3 print i;", "0\n1\n2\n"),
+      ("for i in 3..3 print i; print \"none\";", "none\n"),
+      ("let n = 2; for i in n - 1..n + 1 print i;", "1\n2\n"),
+      ("print 1..4;", "1..4\n"),
+      ("print 0..2 == 0..2;", "true\n"),
+      (
+        "for x in [1, 2, 3, 4] { if (x == 2) continue; if (x == 4) break; print x; }",
+        "1\n3\n",
+      ),
+      (
+        "for i in 0..2 for j in 0..2 print i * 10 + j;",
+        "0\n1\n10\n11\n",
+      ),
+      (
+        "fn sum(xs) { let total = 0; for x in xs { let y = x; total = total + y; } return total; } print sum([1, 2, 3]);",
+        "6\n",
+      ),
+      // Each iteration captures its own variable.
+      (
+        "let fs = []; fs = [nil, nil, nil];
+        for i in 0..3 { fn f() { return i; } fs[i] = f; }
+        print fs[0](); print fs[2]();",
+        "0\n2\n",
+      ),
+      ("let x = \"global\"; for x in [1] {} print x;", "global\n"),
+      (
+        "class Countdown {
+          init(n) { this.n = n; }
+          iter() { return this; }
+          hasNext() { return this.n > 0; }
+          next() { this.n = this.n - 1; return this.n + 1; }
+        }
+        for i in Countdown(3) print i;",
+        "3\n2\n1\n",
+      ),
+      (
+        "class Items {
+          init(xs) { this.xs = xs; }
+          iter() { return ItemsIterator(this.xs); }
+        }
+        class ItemsIterator {
+          init(xs) { this.xs = xs; this.i = 0; }
+          hasNext() { return this.i < len(this.xs); }
+          next() {
+            this.i = this.i + 1;
+            return this.xs[this.i - 1];
+          }
+        }
+        for x in Items([\"a\", \"b\"]) print x;",
+        "a\nb\n",
+      ),
+      // Iterators can yield nil like any other value.
+      (
+        "class Items {
+          init(xs) { this.xs = xs; this.i = 0; }
+          iter() { return this; }
+          hasNext() { return this.i < len(this.xs); }
+          next() { this.i = this.i + 1; return this.xs[this.i - 1]; }
+        }
+        for x in Items([1, nil, 2]) print x;",
+        "1\nnil\n2\n",
+      ),
+    ];
+
+    for (input, expected_output) in test_cases {
+      let (result, output) = interpret(input);
+
+      assert!(matches!(result, InterpretResult::Ok(_)));
+      assert_eq!(expected_output, output);
+    }
+  }
+
+  #[test]
+  fn iterating_non_iterables_returns_runtime_error() {
+    let test_cases: Vec<(&str, RuntimeErrorKind)> = vec![
+      (
+        "for x in 1 print x;",
+        RuntimeErrorKind::NotIterable(Value::Number(1.0)),
+      ),
+      (
+        "for x in nil print x;",
+        RuntimeErrorKind::NotIterable(Value::Nil),
+      ),
+      (
+        "for x in 0..0.5 print x;",
+        RuntimeErrorKind::RangeBoundsMustBeIntegers(Value::Number(0.0), Value::Number(0.5)),
+      ),
+      (
+        "class A { iter() { return this; } } for x in A() print x;",
+        RuntimeErrorKind::UndefinedProperty(String::from("hasNext")),
+      ),
+      (
+        "class A { iter() { return this; } hasNext() { return true; } } for x in A() print x;",
+        RuntimeErrorKind::UndefinedProperty(String::from("next")),
+      ),
+      (
+        "class A { iter() { return 1; } } for x in A() print x;",
+        RuntimeErrorKind::NotAnInstance(Value::Number(1.0)),
+      ),
+    ];
+
+    for (input, expected_kind) in test_cases {
+      match interpret(input) {
+        (InterpretResult::RuntimeError(error), _) => assert_eq!(expected_kind, error.kind),
+        (result, _) => panic!("expected runtime error, got {:?}", result),
+      }
+    }
+  }
+
+  #[test]
+  fn instances_without_iter_method_are_not_iterable() {
+    match interpret("class A {} for x in A() print x;") {
+      (InterpretResult::RuntimeError(error), _) => {
+        assert!(matches!(
+          error.kind,
+          RuntimeErrorKind::NotIterable(Value::Instance(_))
+        ))
+      }
+      (result, _) => panic!("expected runtime error, got {:?}", result),
+    }
+  }
+
+  #[test]
+  fn number_literals() {
+    let test_cases: Vec<(&str, &str)> = vec![
+      ("print 0xFF;", "255\n"),
+      ("print 0b1010;", "10\n"),
+      ("print 0o17;", "15\n"),
+      ("print 1_000_000;", "1000000\n"),
+      ("print 1e3;", "1000\n"),
+      ("print 2.5e-1;", "0.25\n"),
+      ("print 0x10 + 0b10 + 0o10 + 10;", "36\n"),
+      (
+        "print 0x1_0000_0000_0000_0000 == 18446744073709551616;",
+        "true\n",
+      ),
+    ];
+
+    for (input, expected_output) in test_cases {
+      let (result, output) = interpret(input);
+
+      assert!(matches!(result, InterpretResult::Ok(_)));
+      assert_eq!(expected_output, output);
+    }
   }
 }